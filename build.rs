@@ -1,6 +1,7 @@
 use std::env;
 use std::fs;
 use std::path::PathBuf;
+use std::process::Command;
 
 fn main() {
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
@@ -10,5 +11,26 @@ fn main() {
     println!("cargo:rustc-link-search={}", out_dir.display());
     println!("cargo:rerun-if-changed=link.x");
     println!("cargo:rerun-if-changed=src/boot/boot.s");
+    println!("cargo:rerun-if-changed=src/trap/trap.s");
     println!("cargo:rerun-if-changed=build.rs");
+
+    // Unstable features such as `alloc_error_handler` are only used on nightly
+    println!("cargo:rustc-check-cfg=cfg(nightly)");
+    println!("cargo:rerun-if-env-changed=RUSTC_BOOTSTRAP");
+    if is_nightly() {
+        println!("cargo:rustc-cfg=nightly");
+    }
+}
+
+fn is_nightly() -> bool {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .unwrap_or_default();
+
+    version.contains("nightly")
+        || version.contains("-dev")
+        || env::var_os("RUSTC_BOOTSTRAP").is_some()
 }
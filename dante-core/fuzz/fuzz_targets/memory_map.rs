@@ -0,0 +1,31 @@
+//! Build the memory map from arbitrary device trees, run with
+//! `cargo +nightly fuzz run memory_map` from `dante-core`
+
+#![no_main]
+
+use dante_core::addr::PhysAddr;
+use dante_core::memory_map::{MemoryMap, MemoryRegion, RegionKind};
+use dante_core::region::Region;
+use fdt::Fdt;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(fdt) = Fdt::new(data) else {
+        return;
+    };
+
+    let kernel = [MemoryRegion::new(
+        Region::new(PhysAddr::new(0x8020_0000), 0x20_0000),
+        RegionKind::Kernel,
+    )];
+
+    let Ok(map) = MemoryMap::from_fdt(&fdt, PhysAddr::new(0x8700_0000), &kernel) else {
+        return;
+    };
+
+    for usable in map.iter().filter(|r| r.kind == RegionKind::Usable) {
+        for other in map.iter().filter(|r| r.kind != RegionKind::Usable) {
+            assert!(!usable.region.overlaps(&other.region));
+        }
+    }
+});
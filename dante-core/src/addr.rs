@@ -1,18 +1,9 @@
-use core::{fmt, ops};
-
-// TODO: load these from symbols
-pub const RAM_START: usize = 0x80000000;
-pub const PHYSICAL_STACK_START: usize = 0x80000000 + 0x2000000 + 16 * 1024 * 1024;
-pub const RAM_PHYS_START: usize = PHYSICAL_STACK_START + 2 * 1024 * 1024;
-pub const RAM_VIRTUAL_START: u64 = !((1 << 47) - 1);
+//! Physical and virtual addresses.
+//!
+//! Converting between the two depends on where the kernel maps the RAM, see
+//! `PhysAddrExt` and `VirtAddrExt` in the kernel.
 
-extern "C" {
-    #[link_name = "_KERNEL_CODE_VIRTUAL"]
-    pub static KERNEL_CODE_VIRTUAL: u8;
-
-    #[link_name = "_VIRTUAL_STACK"]
-    pub static KERNEL_STACK_VIRTUAL: u8;
-}
+use core::{fmt, ops};
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct VirtAddr(usize);
@@ -22,10 +13,6 @@ impl VirtAddr {
         VirtAddr(addr)
     }
 
-    pub fn to_phys(self) -> PhysAddr {
-        PhysAddr::new(virt_to_phys_addr(self.0))
-    }
-
     pub const fn as_ptr<T>(self) -> *const T {
         self.0 as *const T
     }
@@ -83,10 +70,6 @@ impl PhysAddr {
         PhysAddr(addr)
     }
 
-    pub fn to_virt(self) -> VirtAddr {
-        VirtAddr::new(phys_to_virt_addr(self.0))
-    }
-
     pub const fn as_ptr(&self) -> *const u8 {
         self.0 as *const u8
     }
@@ -136,37 +119,38 @@ impl fmt::Display for PhysAddr {
     }
 }
 
-pub fn virt_to_phys<T: ?Sized>(v: &T) -> usize {
-    let addr = v as *const T as *const () as usize;
-    virt_to_phys_addr(addr)
+pub const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+pub const fn align_down(addr: usize, align: usize) -> usize {
+    addr & !(align - 1)
 }
 
-pub fn virt_to_phys_addr(addr: usize) -> usize {
-    let virtual_code_start = unsafe { &KERNEL_CODE_VIRTUAL as *const _ as usize };
-    let virtual_stack_start = unsafe { &KERNEL_STACK_VIRTUAL as *const _ as usize };
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
 
-    if addr >= virtual_code_start {
-        let offset = addr - virtual_code_start;
-        RAM_START + offset
-    } else if addr >= virtual_stack_start {
-        let offset = addr - virtual_stack_start;
-        PHYSICAL_STACK_START + offset
-    } else {
-        panic!("Unhandled virtual address: 0x{addr:x}")
+    use super::*;
+
+    #[test]
+    fn alignment() {
+        assert_eq!(align_up(0x1001, 0x1000), 0x2000);
+        assert_eq!(align_up(0x2000, 0x1000), 0x2000);
+        assert_eq!(align_down(0x1fff, 0x1000), 0x1000);
+        assert_eq!(PhysAddr::new(0x3000) - PhysAddr::new(0x1000), 0x2000);
+        assert_eq!(format!("{}", VirtAddr::new(0x10)), "0x0000000000000010");
     }
-}
 
-pub fn phys_to_virt_addr(addr: usize) -> usize {
-    let virtual_code_start = unsafe { &KERNEL_CODE_VIRTUAL as *const _ as usize };
-    let virtual_stack_start = unsafe { &KERNEL_STACK_VIRTUAL as *const _ as usize };
-
-    if addr >= RAM_START {
-        let offset = addr - RAM_START;
-        virtual_code_start + offset
-    } else if addr >= PHYSICAL_STACK_START {
-        let offset = addr - PHYSICAL_STACK_START;
-        virtual_stack_start + offset
-    } else {
-        panic!("Unhandled physical address: 0x{addr:x}")
+    proptest! {
+        #[test]
+        fn align_brackets_address(addr in 0..usize::MAX / 2, shift in 0..32u32) {
+            let align = 1 << shift;
+            let (down, up) = (align_down(addr, align), align_up(addr, align));
+
+            prop_assert!(down <= addr && addr <= up);
+            prop_assert!(down.is_multiple_of(align) && up.is_multiple_of(align));
+            prop_assert!(up - down == 0 || up - down == align);
+        }
     }
 }
@@ -0,0 +1,16 @@
+//! Hardware independent parts of the kernel: address arithmetic, page table
+//! entries, the memory map and the SBI return codes.
+//!
+//! The crate is `no_std` so that the kernel can use it, and links `std` when
+//! testing, so that `cargo test` runs its unit and property tests on the host.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod addr;
+pub mod memory_map;
+pub mod page_table;
+pub mod region;
+pub mod sbi;
+
+pub const PAGE_SHIFT: usize = 12;
+pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
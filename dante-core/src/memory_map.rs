@@ -0,0 +1,483 @@
+use core::fmt;
+
+use fdt::Fdt;
+
+use crate::addr::PhysAddr;
+use crate::region::Region;
+
+/// Maximum number of entries in the memory map
+pub const MAX_REGIONS: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemoryMapError {
+    /// The device tree describes more than [`MAX_REGIONS`] regions
+    TooManyRegions,
+    /// The region wraps around the end of the address space
+    InvalidRegion(Region),
+}
+
+impl fmt::Display for MemoryMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryMapError::TooManyRegions => {
+                write!(f, "more than {MAX_REGIONS} regions in the memory map")
+            }
+            MemoryMapError::InvalidRegion(region) => write!(
+                f,
+                "region at {} of {:#x} bytes is past the end of memory",
+                region.start, region.size
+            ),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RegionKind {
+    /// RAM that is free for the kernel to use
+    Usable,
+    /// Memory reserved through `/reserved-memory` or the FDT memreserve block
+    Reserved,
+    /// Memory owned by the SBI firmware, must never be touched
+    Firmware,
+    /// The kernel image, from the boot code to the end of `.bss`
+    Kernel,
+    /// The boot stack
+    Stack,
+    /// The kernel log ring buffer, kept across warm resets
+    Log,
+    /// The flattened device tree blob
+    Dtb,
+    /// The initial ramdisk
+    Initrd,
+}
+
+impl fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RegionKind::Usable => "usable",
+            RegionKind::Reserved => "reserved",
+            RegionKind::Firmware => "firmware",
+            RegionKind::Kernel => "kernel",
+            RegionKind::Stack => "stack",
+            RegionKind::Log => "log",
+            RegionKind::Dtb => "dtb",
+            RegionKind::Initrd => "initrd",
+        };
+
+        name.fmt(f)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MemoryRegion {
+    pub region: Region,
+    pub kind: RegionKind,
+}
+
+impl MemoryRegion {
+    pub const fn new(region: Region, kind: RegionKind) -> Self {
+        Self { region, kind }
+    }
+}
+
+const EMPTY_REGION: MemoryRegion =
+    MemoryRegion::new(Region::new(PhysAddr::new(0), 0), RegionKind::Reserved);
+
+/// Sorted map of the physical memory.
+///
+/// Usable regions are the RAM described by the `memory` nodes of the device
+/// tree, minus every other region of the map, so they never overlap anything.
+#[derive(Clone)]
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_REGIONS],
+    len: usize,
+}
+
+impl MemoryMap {
+    const fn empty() -> Self {
+        Self {
+            regions: [EMPTY_REGION; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Build the map from the device tree at `dtb_addr`, reserving the
+    /// `kernel` regions as well, such as the kernel image and its stack.
+    pub fn from_fdt(
+        fdt: &Fdt<'_>,
+        dtb_addr: PhysAddr,
+        kernel: &[MemoryRegion],
+    ) -> Result<Self, MemoryMapError> {
+        let mut ram = Self::empty();
+        let mut reserved = Self::empty();
+
+        for node in fdt.all_nodes() {
+            let device_type = node.property("device_type").and_then(|p| p.as_str());
+            if device_type != Some("memory") {
+                continue;
+            }
+
+            for reg in node.reg().into_iter().flatten() {
+                if reg.size.is_some_and(|size| size > 0) {
+                    ram.push(Region::from(reg), RegionKind::Usable)?;
+                }
+            }
+        }
+
+        let mut firmware_reserved = false;
+
+        if let Some(reserved_memory) = fdt.find_node("/reserved-memory") {
+            for child in reserved_memory.children() {
+                // OpenSBI names its PMP-protected regions `mmode_resv*` and marks them `no-map`
+                let kind =
+                    if child.property("no-map").is_some() || child.name.starts_with("mmode_resv") {
+                        firmware_reserved = true;
+                        RegionKind::Firmware
+                    } else {
+                        RegionKind::Reserved
+                    };
+
+                for reg in child.reg().into_iter().flatten() {
+                    if reg.size.is_some_and(|size| size > 0) {
+                        reserved.push(Region::from(reg), kind)?;
+                    }
+                }
+            }
+        }
+
+        for reservation in fdt.memory_reservations() {
+            let region = Region::new(
+                PhysAddr::new(reservation.address() as usize),
+                reservation.size(),
+            );
+
+            reserved.push(region, RegionKind::Reserved)?;
+        }
+
+        for region in kernel {
+            reserved.push(region.region, region.kind)?;
+        }
+
+        reserved.push(Region::new(dtb_addr, fdt.total_size()), RegionKind::Dtb)?;
+
+        if let Some(initrd) = initrd_region(fdt) {
+            reserved.push(initrd, RegionKind::Initrd)?;
+        }
+
+        // Without any information from the firmware, assume it lives in the RAM below the kernel
+        let kernel_start = kernel
+            .iter()
+            .find(|r| r.kind == RegionKind::Kernel)
+            .map(|r| r.region.start);
+
+        if !firmware_reserved {
+            if let Some(kernel_start) = kernel_start {
+                if let Some(ram) = ram.iter().find(|r| r.region.contains(kernel_start)) {
+                    if ram.region.start < kernel_start {
+                        let firmware =
+                            Region::new(ram.region.start, kernel_start - ram.region.start);
+                        reserved.push(firmware, RegionKind::Firmware)?;
+                    }
+                }
+            }
+        }
+
+        let mut map = reserved;
+
+        for ram in ram.iter() {
+            map.push_usable(ram.region)?;
+        }
+
+        map.sort();
+        Ok(map)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions[..self.len].iter()
+    }
+
+    /// Regions of RAM available to the kernel
+    pub fn usable(&self) -> impl Iterator<Item = Region> + '_ {
+        self.iter()
+            .filter(|r| r.kind == RegionKind::Usable)
+            .map(|r| r.region)
+    }
+
+    /// Total amount of usable RAM, in bytes
+    pub fn usable_size(&self) -> usize {
+        self.usable().map(|r| r.size).sum()
+    }
+
+    /// The memory the kernel may access, which is every region except the
+    /// firmware's, merged into the largest contiguous regions
+    pub fn accessible(&self) -> Accessible<'_> {
+        Accessible {
+            regions: self.regions[..self.len].iter(),
+        }
+    }
+
+    fn push(&mut self, region: Region, kind: RegionKind) -> Result<(), MemoryMapError> {
+        if region.start.as_usize().checked_add(region.size).is_none() {
+            return Err(MemoryMapError::InvalidRegion(region));
+        }
+
+        let slot = self
+            .regions
+            .get_mut(self.len)
+            .ok_or(MemoryMapError::TooManyRegions)?;
+
+        *slot = MemoryRegion::new(region, kind);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Add the parts of `ram` that do not overlap any region of the map
+    fn push_usable(&mut self, ram: Region) -> Result<(), MemoryMapError> {
+        let mut start = ram.start;
+
+        while start < ram.end() {
+            // The first region overlapping what is left of the RAM
+            let next = self
+                .iter()
+                .filter(|r| r.kind != RegionKind::Usable)
+                .map(|r| r.region)
+                .filter(|r| r.end() > start && r.start < ram.end())
+                .min_by_key(|r| r.start);
+
+            match next {
+                Some(reserved) => {
+                    if reserved.start > start {
+                        self.push(
+                            Region::new(start, reserved.start - start),
+                            RegionKind::Usable,
+                        )?;
+                    }
+
+                    start = reserved.end();
+                }
+                None => {
+                    self.push(Region::new(start, ram.end() - start), RegionKind::Usable)?;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sort(&mut self) {
+        self.regions[..self.len].sort_unstable_by_key(|r| (r.region.start, r.kind));
+    }
+}
+
+/// Iterator returned by [`MemoryMap::accessible`]
+pub struct Accessible<'a> {
+    regions: core::slice::Iter<'a, MemoryRegion>,
+}
+
+impl Iterator for Accessible<'_> {
+    type Item = Region;
+
+    fn next(&mut self) -> Option<Region> {
+        let mut regions = self
+            .regions
+            .by_ref()
+            .filter(|r| r.kind != RegionKind::Firmware)
+            .map(|r| r.region);
+
+        let mut merged = regions.next()?;
+
+        // The regions are sorted, a region starting after the end of the merged one ends it
+        for region in self.regions.clone() {
+            if region.kind == RegionKind::Firmware || region.region.start > merged.end() {
+                break;
+            }
+
+            let end = region.region.end().max(merged.end());
+            merged = Region::new(merged.start, end - merged.start);
+            self.regions.next();
+        }
+
+        Some(merged)
+    }
+}
+
+impl fmt::Debug for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for region in self.iter() {
+            writeln!(f, "{:<8} {}", region.kind, region.region)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn initrd_region(fdt: &Fdt<'_>) -> Option<Region> {
+    let chosen = fdt.find_node("/chosen")?;
+    let start = chosen.property("linux,initrd-start")?.as_usize()?;
+    let end = chosen.property("linux,initrd-end")?.as_usize()?;
+
+    (end > start).then(|| Region::new(PhysAddr::new(start), end - start))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Device tree of QEMU virt with 128 MiB of RAM, without the firmware reservations
+    static VIRT_DTB: &[u8] = include_bytes!("../../virt.dtb");
+
+    const RAM: Region = Region::new(PhysAddr::new(0x8000_0000), 0x800_0000);
+
+    fn kernel_regions(kernel: PhysAddr, kernel_size: usize) -> [MemoryRegion; 2] {
+        [
+            MemoryRegion::new(Region::new(kernel, kernel_size), RegionKind::Kernel),
+            MemoryRegion::new(
+                Region::new(kernel + kernel_size, 0x10_0000),
+                RegionKind::Stack,
+            ),
+        ]
+    }
+
+    fn kind_at(map: &MemoryMap, addr: PhysAddr) -> Option<RegionKind> {
+        map.iter()
+            .filter(|r| r.region.contains(addr))
+            .map(|r| r.kind)
+            .min()
+    }
+
+    #[test]
+    fn virt_memory_map() {
+        let fdt = Fdt::new(VIRT_DTB).unwrap();
+        let kernel = PhysAddr::new(0x8020_0000);
+        let dtb_addr = PhysAddr::new(0x8700_0000);
+        let map = MemoryMap::from_fdt(&fdt, dtb_addr, &kernel_regions(kernel, 0x10_0000)).unwrap();
+
+        let kinds: Vec<_> = map.iter().map(|r| r.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                RegionKind::Firmware,
+                RegionKind::Kernel,
+                RegionKind::Stack,
+                RegionKind::Usable,
+                RegionKind::Dtb,
+                RegionKind::Usable,
+            ]
+        );
+
+        assert_eq!(kind_at(&map, RAM.start), Some(RegionKind::Firmware));
+        assert_eq!(kind_at(&map, dtb_addr), Some(RegionKind::Dtb));
+        assert_eq!(map.usable_size(), RAM.size - 0x40_0000 - fdt.total_size());
+    }
+
+    #[test]
+    fn accessible_memory() {
+        let fdt = Fdt::new(VIRT_DTB).unwrap();
+        let kernel = PhysAddr::new(0x8020_0000);
+        let map = MemoryMap::from_fdt(
+            &fdt,
+            PhysAddr::new(0x8700_0000),
+            &kernel_regions(kernel, 0x10_0000),
+        )
+        .unwrap();
+
+        let accessible: Vec<_> = map.accessible().collect();
+        assert_eq!(accessible, [Region::new(kernel, RAM.end() - kernel)]);
+
+        let map = MemoryMap::from_fdt(&fdt, PhysAddr::new(0x9000_0000), &[]).unwrap();
+        let accessible: Vec<_> = map.accessible().collect();
+        assert_eq!(
+            accessible,
+            [
+                RAM,
+                Region::new(PhysAddr::new(0x9000_0000), fdt.total_size())
+            ]
+        );
+    }
+
+    #[test]
+    fn firmware_is_only_guessed_below_the_kernel() {
+        let fdt = Fdt::new(VIRT_DTB).unwrap();
+        let map = MemoryMap::from_fdt(&fdt, PhysAddr::new(0x8700_0000), &[]).unwrap();
+
+        assert_eq!(kind_at(&map, RAM.start), Some(RegionKind::Usable));
+        assert!(map.iter().all(|r| r.kind != RegionKind::Firmware));
+    }
+
+    #[test]
+    fn invalid_memory_maps() {
+        let fdt = Fdt::new(VIRT_DTB).unwrap();
+        let dtb_addr = PhysAddr::new(0x8700_0000);
+
+        let wrapping = Region::new(PhysAddr::new(usize::MAX - 0xfff), 0x2000);
+        let kernel = [MemoryRegion::new(wrapping, RegionKind::Kernel)];
+        assert_eq!(
+            MemoryMap::from_fdt(&fdt, dtb_addr, &kernel).unwrap_err(),
+            MemoryMapError::InvalidRegion(wrapping)
+        );
+
+        let kernel =
+            [MemoryRegion::new(Region::new(RAM.start, 0x1000), RegionKind::Kernel); MAX_REGIONS];
+        assert_eq!(
+            MemoryMap::from_fdt(&fdt, dtb_addr, &kernel).unwrap_err(),
+            MemoryMapError::TooManyRegions
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn usable_memory_is_never_reserved(
+            kernel in 0..0x800usize,
+            kernel_size in 1..0x100_0000usize,
+            dtb in 0..0x800_0000usize,
+            addr in 0..0x800_0000usize,
+        ) {
+            let fdt = Fdt::new(VIRT_DTB).unwrap();
+            let kernel = RAM.start + kernel * 0x1_0000;
+            let map = MemoryMap::from_fdt(
+                &fdt,
+                RAM.start + dtb,
+                &kernel_regions(kernel, kernel_size),
+            )
+            .unwrap();
+
+            for (a, b) in map.iter().zip(map.iter().skip(1)) {
+                prop_assert!(a.region.start <= b.region.start);
+            }
+
+            for usable in map.iter().filter(|r| r.kind == RegionKind::Usable) {
+                prop_assert!(usable.region.size > 0);
+                prop_assert!(RAM.start <= usable.region.start && usable.region.end() <= RAM.end());
+
+                for other in map.iter().filter(|&r| r != usable) {
+                    prop_assert!(!usable.region.overlaps(&other.region), "{:?} overlaps {:?}", usable, other);
+                }
+            }
+
+            // Every byte of RAM is either usable or reserved
+            let addr = RAM.start + addr;
+            let regions = map.iter().filter(|r| r.region.contains(addr)).count();
+            prop_assert!(regions >= 1);
+
+            let accessible: Vec<_> = map.accessible().collect();
+
+            for (a, b) in accessible.iter().zip(accessible.iter().skip(1)) {
+                prop_assert!(a.end() < b.start);
+            }
+
+            for usable in map.usable() {
+                prop_assert!(accessible.iter().any(|r| r.start <= usable.start && usable.end() <= r.end()));
+            }
+        }
+    }
+}
@@ -0,0 +1,878 @@
+use core::fmt::{self, Write};
+use core::ops;
+
+use fdt::Fdt;
+
+use crate::addr::{PhysAddr, VirtAddr};
+use crate::{PAGE_SHIFT, PAGE_SIZE};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PtError {
+    AlreadyMappedLeaf,
+    AlreadMappedIntermediate,
+    /// An address or size is not aligned on a page, or a range only covers part of a leaf
+    Misaligned,
+    /// The range overlaps an existing mapping
+    Overlapping,
+    /// No frame was available for an intermediate page table
+    OutOfMemory,
+    /// The address is not mapped
+    NotMapped,
+    /// A leaf must be at least readable or executable
+    InvalidFlags,
+    /// The entry uses a reserved encoding
+    InvalidEntry(PteError),
+    /// The address is not canonical in the current paging mode
+    NonCanonical,
+}
+
+/// Virtual memory schemes supported by the kernel, ordered by number of levels
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PagingMode {
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+impl PagingMode {
+    /// All the modes, from the largest address space to the smallest
+    pub const ALL: [PagingMode; 3] = [PagingMode::Sv57, PagingMode::Sv48, PagingMode::Sv39];
+
+    pub const fn levels(self) -> u8 {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+            PagingMode::Sv57 => 5,
+        }
+    }
+
+    /// Level of the root page table
+    pub const fn top_level(self) -> u8 {
+        self.levels() - 1
+    }
+
+    pub const fn va_bits(self) -> u32 {
+        12 + 9 * self.levels() as u32
+    }
+
+    /// Value of the `MODE` field of `satp`
+    pub const fn satp_mode(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 8,
+            PagingMode::Sv48 => 9,
+            PagingMode::Sv57 => 10,
+        }
+    }
+
+    /// First address of the upper half of the address space, where the kernel lives
+    pub const fn upper_half_start(self) -> VirtAddr {
+        VirtAddr::new(!((1 << (self.va_bits() - 1)) - 1))
+    }
+
+    /// Sign-extend `addr` from its highest valid bit
+    pub fn canonicalize(self, addr: usize) -> VirtAddr {
+        let shift = usize::BITS - self.va_bits();
+        VirtAddr::new(((addr << shift) as isize >> shift) as usize)
+    }
+
+    /// Addresses must be sign-extended from their highest valid bit
+    pub fn is_canonical(self, virt: VirtAddr) -> bool {
+        let high = virt.as_usize() as isize >> (self.va_bits() - 1);
+        high == 0 || high == -1
+    }
+
+    pub fn from_satp(satp: usize) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.satp_mode() == satp >> 60)
+    }
+
+    fn from_mmu_type(mmu_type: &str) -> Option<Self> {
+        match mmu_type {
+            "riscv,sv39" => Some(PagingMode::Sv39),
+            "riscv,sv48" => Some(PagingMode::Sv48),
+            "riscv,sv57" => Some(PagingMode::Sv57),
+            _ => None,
+        }
+    }
+
+    /// The largest mode supported by every hart, according to their `mmu-type`
+    pub fn from_fdt(fdt: &Fdt<'_>) -> Option<Self> {
+        fdt.cpus()
+            .map(|cpu| {
+                cpu.property("mmu-type")
+                    .and_then(|p| p.as_str())
+                    .and_then(Self::from_mmu_type)
+            })
+            .min()
+            .flatten()
+    }
+}
+
+impl fmt::Display for PagingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PagingMode::Sv39 => "Sv39",
+            PagingMode::Sv48 => "Sv48",
+            PagingMode::Sv57 => "Sv57",
+        };
+
+        name.fmt(f)
+    }
+}
+
+/// Sizes of the leaves supported by the page table code
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    /// All the sizes, from the largest to the smallest
+    pub const ALL: [PageSize; 3] = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K];
+
+    pub const fn level(self) -> u8 {
+        match self {
+            PageSize::Size4K => 0,
+            PageSize::Size2M => 1,
+            PageSize::Size1G => 2,
+        }
+    }
+
+    pub const fn size(self) -> usize {
+        PAGE_SIZE << (9 * self.level())
+    }
+
+    pub fn from_level(level: u8) -> Option<Self> {
+        match level {
+            0 => Some(PageSize::Size4K),
+            1 => Some(PageSize::Size2M),
+            2 => Some(PageSize::Size1G),
+            _ => None,
+        }
+    }
+}
+
+/// Result of a successful `RootPageTable::translate`
+#[derive(Copy, Clone, Debug)]
+pub struct Translation {
+    pub phys: PhysAddr,
+    pub flags: PteFlags,
+    pub page_size: PageSize,
+}
+
+/// The permission and status bits of a page table entry
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct PteFlags(u8);
+
+impl PteFlags {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether all the flags of `other` are set
+    pub const fn contains(self, other: PteFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether any flag of `other` is set
+    pub const fn intersects(self, other: PteFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl ops::BitOr for PteFlags {
+    type Output = PteFlags;
+
+    fn bitor(self, rhs: PteFlags) -> PteFlags {
+        PteFlags(self.0 | rhs.0)
+    }
+}
+
+impl ops::BitOrAssign for PteFlags {
+    fn bitor_assign(&mut self, rhs: PteFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl ops::BitAnd for PteFlags {
+    type Output = PteFlags;
+
+    fn bitand(self, rhs: PteFlags) -> PteFlags {
+        PteFlags(self.0 & rhs.0)
+    }
+}
+
+impl ops::Not for PteFlags {
+    type Output = PteFlags;
+
+    fn not(self) -> PteFlags {
+        PteFlags(!self.0)
+    }
+}
+
+macro_rules! declare_flags {
+     (flags {
+         $($flag:ident : $value:expr),* $(,)?
+     }) => {
+         $(
+             pub const $flag: PteFlags = PteFlags(1 << $value);
+         )*
+
+         const FLAGS: [(u8, &'static str); 8] = [
+            $(($value, stringify!($flag)),)*
+         ];
+     };
+ }
+
+declare_flags! {
+    flags {
+        PTE_VALID: 0,
+        PTE_READ: 1,
+        PTE_WRITE: 2,
+        PTE_EXECUTE: 3,
+        PTE_USER: 4,
+        PTE_GLOBAL: 5,
+        PTE_ACCESSED: 6,
+        PTE_DIRTY: 7,
+    }
+}
+
+/// Any of these flags makes an entry a leaf, otherwise it points to the next level
+pub const PTE_LEAF: PteFlags = PteFlags(PTE_READ.0 | PTE_WRITE.0 | PTE_EXECUTE.0);
+
+impl fmt::Debug for PteFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut flags = f.debug_set();
+
+        for (flag, name) in FLAGS {
+            if self.0 & (1 << flag) != 0 {
+                flags.entry(&name);
+            }
+        }
+
+        flags.finish()
+    }
+}
+
+/// One letter per flag, `-` when it is clear, e.g. `vrw---ad`
+impl fmt::Display for PteFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (flag, letter) in (0..8).zip("vrwxugad".chars()) {
+            f.write_char(if self.0 & (1 << flag) != 0 {
+                letter
+            } else {
+                '-'
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Memory type of a leaf, with the Svpbmt extension
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum MemoryType {
+    /// The attributes of the physical memory, e.g. cacheable RAM
+    #[default]
+    Pma,
+    /// Non-cacheable, idempotent, weakly-ordered main memory
+    Nc,
+    /// Non-cacheable, non-idempotent, strongly-ordered I/O memory
+    Io,
+}
+
+impl MemoryType {
+    const fn bits(self) -> u64 {
+        match self {
+            MemoryType::Pma => 0,
+            MemoryType::Nc => 1,
+            MemoryType::Io => 2,
+        }
+    }
+
+    fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0 => Some(MemoryType::Pma),
+            1 => Some(MemoryType::Nc),
+            2 => Some(MemoryType::Io),
+            _ => None,
+        }
+    }
+}
+
+/// Encodings of a page table entry reserved by the privileged specification
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PteError {
+    /// Writable pages must also be readable
+    WriteWithoutRead,
+    /// The PBMT field holds the reserved value 3
+    ReservedMemoryType,
+    /// Bits 54 to 60 are set
+    ReservedBits,
+    /// A non-leaf entry has the D, A, U, PBMT or N bits set
+    NonLeafAttributes,
+    /// A NAPOT entry does not encode a 64 KiB page
+    InvalidNapot,
+}
+
+impl fmt::Display for PteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            PteError::WriteWithoutRead => "writable page is not readable",
+            PteError::ReservedMemoryType => "reserved memory type",
+            PteError::ReservedBits => "reserved bits are set",
+            PteError::NonLeafAttributes => "non-leaf entry has leaf attributes",
+            PteError::InvalidNapot => "invalid NAPOT page size",
+        };
+
+        msg.fmt(f)
+    }
+}
+
+const PPN_SHIFT: u32 = 10;
+const PPN_MASK: u64 = (1 << 44) - 1;
+const RSW_SHIFT: u32 = 8;
+const RSW_MASK: u64 = 0b11;
+const RESERVED_MASK: u64 = 0x7f << 54;
+const PBMT_SHIFT: u32 = 61;
+const PBMT_MASK: u64 = 0b11;
+const NAPOT: u64 = 1 << 63;
+/// Low bits of the PPN of a NAPOT entry, which encode a 64 KiB page
+const NAPOT_64K: u64 = 0b1000;
+
+/// An entry of a page table.
+///
+/// ```text
+/// 63  62-61  60-54     53-10  9-8  7 6 5 4 3 2 1 0
+/// N   PBMT   reserved  PPN    RSW  D A G U X W R V
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    pub const EMPTY: PageTableEntry = PageTableEntry(0);
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// A valid entry pointing to `ppn`, a leaf if `flags` has any of R, W or X
+    pub fn new(ppn: u64, flags: PteFlags) -> Self {
+        Self((ppn & PPN_MASK) << PPN_SHIFT | (flags | PTE_VALID).0 as u64)
+    }
+
+    pub fn ppn(&self) -> u64 {
+        self.0 >> PPN_SHIFT & PPN_MASK
+    }
+
+    pub fn flags(&self) -> PteFlags {
+        PteFlags(self.0 as u8)
+    }
+
+    /// The same entry with its flags replaced by `flags`
+    pub fn with_flags(self, flags: PteFlags) -> Self {
+        Self(self.0 & !0xff | flags.0 as u64)
+    }
+
+    /// The two bits reserved for the supervisor software
+    pub fn rsw(&self) -> u8 {
+        (self.0 >> RSW_SHIFT & RSW_MASK) as u8
+    }
+
+    pub const fn with_rsw(self, rsw: u8) -> Self {
+        Self(self.0 & !(RSW_MASK << RSW_SHIFT) | (rsw as u64 & RSW_MASK) << RSW_SHIFT)
+    }
+
+    /// The Svpbmt memory type, `None` for the reserved encoding
+    pub fn memory_type(&self) -> Option<MemoryType> {
+        MemoryType::from_bits(self.0 >> PBMT_SHIFT & PBMT_MASK)
+    }
+
+    pub fn with_memory_type(self, memory_type: MemoryType) -> Self {
+        Self(self.0 & !(PBMT_MASK << PBMT_SHIFT) | memory_type.bits() << PBMT_SHIFT)
+    }
+
+    /// Whether the Svnapot N bit is set, the leaf is then part of a 64 KiB page
+    pub fn is_napot(&self) -> bool {
+        self.0 & NAPOT != 0
+    }
+
+    /// Make the leaf part of a 64 KiB NAPOT page, whose PPN must be 64 KiB aligned
+    pub fn with_napot(self) -> Self {
+        Self(self.0 & !(0b1111 << PPN_SHIFT) | NAPOT_64K << PPN_SHIFT | NAPOT)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.flags().contains(PTE_VALID)
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.flags().intersects(PTE_LEAF)
+    }
+
+    /// The physical address of the page or table pointed to by the entry.
+    ///
+    /// For a NAPOT entry, the address of the 64 KiB page.
+    pub fn addr(&self) -> PhysAddr {
+        let ppn = if self.is_napot() {
+            self.ppn() & !0b1111
+        } else {
+            self.ppn()
+        };
+
+        PhysAddr::new((ppn as usize) << PAGE_SHIFT)
+    }
+
+    /// Check that a valid entry does not use any reserved encoding.
+    /// The other bits of invalid entries are ignored by the hardware.
+    pub fn validate(&self) -> Result<(), PteError> {
+        if !self.is_valid() {
+            return Ok(());
+        }
+
+        let flags = self.flags();
+
+        if flags.contains(PTE_WRITE) && !flags.contains(PTE_READ) {
+            Err(PteError::WriteWithoutRead)
+        } else if self.memory_type().is_none() {
+            Err(PteError::ReservedMemoryType)
+        } else if self.0 & RESERVED_MASK != 0 {
+            Err(PteError::ReservedBits)
+        } else if !self.is_leaf()
+            && (flags.intersects(PTE_DIRTY | PTE_ACCESSED | PTE_USER)
+                || self.memory_type() != Some(MemoryType::Pma)
+                || self.is_napot())
+        {
+            Err(PteError::NonLeafAttributes)
+        } else if self.is_napot() && self.ppn() & 0b1111 != NAPOT_64K {
+            Err(PteError::InvalidNapot)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+struct Ppn(u64);
+
+impl fmt::Debug for Ppn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)
+    }
+}
+
+impl fmt::Debug for PageTableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut entry = f.debug_struct("PageTableEntry");
+
+        entry
+            .field("ppn", &Ppn(self.ppn()))
+            .field("rsw", &self.rsw())
+            .field("flags", &self.flags());
+
+        match self.memory_type() {
+            Some(MemoryType::Pma) => {}
+            Some(memory_type) => {
+                entry.field("memory_type", &memory_type);
+            }
+            None => {
+                entry.field("memory_type", &"reserved");
+            }
+        }
+
+        if self.is_napot() {
+            entry.field("napot", &true);
+        }
+
+        entry.finish()
+    }
+}
+
+/// Leaves mapping a contiguous virtual range to contiguous physical memory,
+/// all with the same flags and at the same level
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: usize,
+    pub flags: PteFlags,
+    pub level: u8,
+}
+
+impl Mapping {
+    /// Exclusive end, 0 for a mapping which ends at the top of the address space
+    pub fn end(&self) -> VirtAddr {
+        VirtAddr::new(self.virt.as_usize().wrapping_add(self.size))
+    }
+
+    pub fn phys_end(&self) -> PhysAddr {
+        self.phys + self.size
+    }
+
+    /// Extend the mapping with `next` if it continues it, returning whether it did
+    pub fn merge(&mut self, next: &Mapping) -> bool {
+        let continues = self.virt.as_usize().checked_add(self.size) == Some(next.virt.as_usize())
+            && next.phys == self.phys_end()
+            && next.flags == self.flags
+            && next.level == self.level;
+
+        if continues {
+            self.size += next.size;
+        }
+
+        continues
+    }
+}
+
+/// `va range -> pa range, size, flags, level`
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{} -> {}-{} {:>#12x} {} L{}",
+            self.virt,
+            self.end(),
+            self.phys,
+            self.phys_end(),
+            self.size,
+            self.flags,
+            self.level
+        )
+    }
+}
+
+/// Invariants of a page table which can be broken
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// The entry uses a reserved encoding, e.g. W without R
+    InvalidEntry(PteError),
+    /// A leaf above the last level points to memory not aligned on its size
+    MisalignedLeaf,
+    /// An entry of the last level points to another table
+    TableAtLastLevel,
+    /// Memory mapped executable is also writable through another mapping
+    WritableCodeAlias,
+    /// A table which is not from the frame allocator is not marked as static
+    UnmarkedStaticTable,
+    /// A table from the frame allocator is marked as static, and would never be freed
+    MarkedDynamicTable,
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::InvalidEntry(err) => write!(f, "invalid entry: {err}"),
+            Inconsistency::MisalignedLeaf => "misaligned leaf".fmt(f),
+            Inconsistency::TableAtLastLevel => "table pointer at the last level".fmt(f),
+            Inconsistency::WritableCodeAlias => "executable memory is also mapped writable".fmt(f),
+            Inconsistency::UnmarkedStaticTable => "static table is not marked".fmt(f),
+            Inconsistency::MarkedDynamicTable => "allocated table is marked static".fmt(f),
+        }
+    }
+}
+
+/// An [`Inconsistency`] found at `virt`, in a table of level `level`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub virt: VirtAddr,
+    pub level: u8,
+    pub kind: Inconsistency,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (L{}): {}", self.virt, self.level, self.kind)
+    }
+}
+
+/// Check a valid entry of a table of level `level`, on its own
+pub fn check_entry(entry: &PageTableEntry, level: u8) -> Result<(), Inconsistency> {
+    entry.validate().map_err(Inconsistency::InvalidEntry)?;
+
+    if !entry.is_leaf() {
+        return if level == 0 {
+            Err(Inconsistency::TableAtLastLevel)
+        } else {
+            Ok(())
+        };
+    }
+
+    let alignment = PAGE_SIZE << (9 * level);
+
+    if entry.addr().as_usize().is_multiple_of(alignment) {
+        Ok(())
+    } else {
+        Err(Inconsistency::MisalignedLeaf)
+    }
+}
+
+/// Index of the entry mapping `addr` in a page table of level `idx`
+pub fn vpn(addr: u64, idx: u8) -> u16 {
+    ((addr >> (12 + 9 * idx)) & ((1 << 9) - 1)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn vpn_levels() {
+        let addr = 0x7f_c020_3000;
+
+        assert_eq!(vpn(addr, 0), 3);
+        assert_eq!(vpn(addr, 1), 1);
+        assert_eq!(vpn(addr, 2), 0x1ff);
+    }
+
+    #[test]
+    fn canonical_addresses() {
+        let mode = PagingMode::Sv39;
+
+        assert!(mode.is_canonical(VirtAddr::new(0x3f_ffff_f000)));
+        assert!(!mode.is_canonical(VirtAddr::new(0x40_0000_0000)));
+        assert!(mode.is_canonical(mode.upper_half_start()));
+        assert_eq!(
+            mode.canonicalize(0x7f_c000_0000),
+            VirtAddr::new(0xffff_ffff_c000_0000)
+        );
+    }
+
+    #[test]
+    fn page_sizes() {
+        assert_eq!(PageSize::Size4K.size(), 0x1000);
+        assert_eq!(PageSize::Size2M.size(), 0x20_0000);
+        assert_eq!(PageSize::Size1G.size(), 0x4000_0000);
+
+        for page_size in PageSize::ALL {
+            assert_eq!(PageSize::from_level(page_size.level()), Some(page_size));
+        }
+    }
+
+    #[test]
+    fn satp_modes() {
+        for mode in PagingMode::ALL {
+            assert_eq!(
+                PagingMode::from_satp(mode.satp_mode() << 60 | 0x1234),
+                Some(mode)
+            );
+        }
+
+        assert_eq!(PagingMode::from_satp(0), None);
+    }
+
+    #[test]
+    fn entry_debug() {
+        let entry = PageTableEntry::new(0x80200, PTE_READ | PTE_WRITE | PTE_DIRTY).with_rsw(0b10);
+
+        assert_eq!(
+            format!("{entry:?}"),
+            "PageTableEntry { ppn: 0x0000000000080200, rsw: 2, flags: {\"PTE_VALID\", \"PTE_READ\", \"PTE_WRITE\", \"PTE_DIRTY\"} }"
+        );
+
+        let entry = PageTableEntry::new(0x10000, PTE_READ)
+            .with_memory_type(MemoryType::Io)
+            .with_napot();
+
+        assert_eq!(
+            format!("{entry:?}"),
+            "PageTableEntry { ppn: 0x0000000000010008, rsw: 0, flags: {\"PTE_VALID\", \"PTE_READ\"}, memory_type: Io, napot: true }"
+        );
+    }
+
+    #[test]
+    fn reserved_encodings() {
+        let leaf = PageTableEntry::new(0x80200, PTE_READ);
+        let table = PageTableEntry::new(0x80200, PteFlags::empty());
+
+        assert_eq!(leaf.validate(), Ok(()));
+        assert_eq!(table.validate(), Ok(()));
+        assert_eq!(
+            PageTableEntry::new(0x80200, PTE_WRITE).validate(),
+            Err(PteError::WriteWithoutRead)
+        );
+        assert_eq!(
+            PageTableEntry::from_bits(leaf.bits() | 3 << 61).validate(),
+            Err(PteError::ReservedMemoryType)
+        );
+        assert_eq!(
+            PageTableEntry::from_bits(leaf.bits() | 1 << 54).validate(),
+            Err(PteError::ReservedBits)
+        );
+        assert_eq!(
+            PageTableEntry::new(0x80200, PTE_ACCESSED).validate(),
+            Err(PteError::NonLeafAttributes)
+        );
+        assert_eq!(
+            table.with_memory_type(MemoryType::Nc).validate(),
+            Err(PteError::NonLeafAttributes)
+        );
+        assert_eq!(
+            PageTableEntry::from_bits(leaf.bits() | 1 << 63).validate(),
+            Err(PteError::InvalidNapot)
+        );
+        assert_eq!(leaf.with_napot().validate(), Ok(()));
+
+        // The MMU ignores everything else in invalid entries
+        assert_eq!(PageTableEntry::from_bits(!1).validate(), Ok(()));
+    }
+
+    #[test]
+    fn napot_address() {
+        let entry = PageTableEntry::new(0x80210, PTE_READ).with_napot();
+
+        assert_eq!(entry.ppn(), 0x80218);
+        assert_eq!(entry.addr(), PhysAddr::new(0x8021_0000));
+    }
+
+    #[test]
+    fn entry_checks() {
+        let leaf_2m = PageTableEntry::new(0x80200, PTE_READ | PTE_EXECUTE);
+
+        assert_eq!(check_entry(&leaf_2m, 1), Ok(()));
+        assert_eq!(check_entry(&leaf_2m, 2), Err(Inconsistency::MisalignedLeaf));
+        assert_eq!(
+            check_entry(&PageTableEntry::new(0x80201, PTE_READ), 1),
+            Err(Inconsistency::MisalignedLeaf)
+        );
+        assert_eq!(
+            check_entry(&PageTableEntry::new(0x80201, PteFlags::empty()), 0),
+            Err(Inconsistency::TableAtLastLevel)
+        );
+        assert_eq!(
+            check_entry(&PageTableEntry::new(0x80201, PTE_WRITE), 0),
+            Err(Inconsistency::InvalidEntry(PteError::WriteWithoutRead))
+        );
+    }
+
+    #[test]
+    fn mapping_merge() {
+        let mapping = |virt, phys, size, flags| Mapping {
+            virt: VirtAddr::new(virt),
+            phys: PhysAddr::new(phys),
+            size,
+            flags,
+            level: 0,
+        };
+
+        let mut text = mapping(0xffff_1000, 0x8000_1000, 0x1000, PTE_READ | PTE_EXECUTE);
+
+        assert!(text.merge(&mapping(
+            0xffff_2000,
+            0x8000_2000,
+            0x1000,
+            PTE_READ | PTE_EXECUTE
+        )));
+        assert!(!text.merge(&mapping(0xffff_3000, 0x8000_3000, 0x1000, PTE_READ)));
+        assert!(!text.merge(&mapping(
+            0xffff_3000,
+            0x8000_4000,
+            0x1000,
+            PTE_READ | PTE_EXECUTE
+        )));
+        assert_eq!(text.size, 0x2000);
+        assert_eq!(
+            text.to_string(),
+            "0x00000000ffff1000-0x00000000ffff3000 -> 0x0000000080001000-0x0000000080003000       0x2000 -r-x---- L0"
+        );
+    }
+
+    #[test]
+    fn mapping_at_top_of_address_space() {
+        let mut top = Mapping {
+            virt: VirtAddr::new(0xffff_ffff_c000_0000),
+            phys: PhysAddr::new(0x8000_0000),
+            size: 1 << 30,
+            flags: PTE_READ | PTE_WRITE,
+            level: 2,
+        };
+
+        assert_eq!(top.end(), VirtAddr::new(0));
+        assert!(!top.merge(&Mapping {
+            virt: VirtAddr::new(0),
+            phys: PhysAddr::new(0xc000_0000),
+            ..top
+        }));
+        assert!(top
+            .to_string()
+            .starts_with("0xffffffffc0000000-0x0000000000000000"));
+    }
+
+    proptest! {
+        #[test]
+        fn entry_encoding(ppn in 0..1u64 << 44, rsw in 0..4u8, flags: u8, memory_type in 0..3u64) {
+            let flags = PteFlags::from_bits(flags);
+            let memory_type = MemoryType::from_bits(memory_type).unwrap();
+            let entry = PageTableEntry::new(ppn, flags)
+                .with_rsw(rsw)
+                .with_memory_type(memory_type);
+
+            prop_assert_eq!(entry.ppn(), ppn);
+            prop_assert_eq!(entry.rsw(), rsw);
+            prop_assert_eq!(entry.flags(), flags | PTE_VALID);
+            prop_assert_eq!(entry.memory_type(), Some(memory_type));
+            prop_assert!(!entry.is_napot());
+            prop_assert_eq!(entry.addr().as_usize(), (ppn as usize) << PAGE_SHIFT);
+            prop_assert!(entry.is_valid());
+            prop_assert_eq!(entry.is_leaf(), flags.intersects(PTE_LEAF));
+            prop_assert_eq!(PageTableEntry::from_bits(entry.bits()), entry);
+        }
+
+        #[test]
+        fn with_flags_keeps_the_rest(bits: u64, flags: u8) {
+            let entry = PageTableEntry::from_bits(bits).with_flags(PteFlags::from_bits(flags));
+
+            prop_assert_eq!(entry.bits() & !0xff, bits & !0xff);
+            prop_assert_eq!(entry.flags().bits(), flags);
+        }
+
+        #[test]
+        fn writable_entries_are_readable(bits: u64) {
+            let entry = PageTableEntry::from_bits(bits);
+            let flags = entry.flags();
+
+            if entry.validate().is_ok() && flags.contains(PTE_VALID | PTE_WRITE) {
+                prop_assert!(flags.contains(PTE_READ));
+            }
+        }
+
+        #[test]
+        fn vpn_covers_the_address(addr in 0..1u64 << 57) {
+            let page_number = (0..5).fold(0, |acc, level| {
+                acc | (vpn(addr, level) as u64) << (PAGE_SHIFT as u64 + 9 * level as u64)
+            });
+
+            prop_assert_eq!(page_number | (addr % PAGE_SIZE as u64), addr);
+        }
+
+        #[test]
+        fn canonical_addresses_are_sign_extended(addr: usize) {
+            for mode in PagingMode::ALL {
+                let canonical = mode.canonicalize(addr);
+
+                prop_assert!(mode.is_canonical(canonical));
+                prop_assert_eq!(mode.is_canonical(VirtAddr::new(addr)), canonical.as_usize() == addr);
+            }
+        }
+    }
+}
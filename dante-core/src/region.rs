@@ -0,0 +1,79 @@
+use core::fmt;
+
+use crate::addr::PhysAddr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Region {
+    pub start: PhysAddr,
+    pub size: usize,
+}
+
+impl Region {
+    pub const fn new(start: PhysAddr, size: usize) -> Self {
+        Self { start, size }
+    }
+
+    pub fn end(&self) -> PhysAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: PhysAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    pub fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+impl From<fdt::standard_nodes::MemoryRegion> for Region {
+    fn from(region: fdt::standard_nodes::MemoryRegion) -> Self {
+        Self {
+            start: PhysAddr::new(region.starting_address as usize),
+            size: region.size.unwrap(),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} - {} ({} bytes)", self.start, self.end(), self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn region() -> impl Strategy<Value = Region> {
+        (0..1usize << 40, 0..1usize << 32)
+            .prop_map(|(start, size)| Region::new(PhysAddr::new(start), size))
+    }
+
+    #[test]
+    fn region_arithmetic() {
+        let a = Region::new(PhysAddr::new(0x1000), 0x2000);
+        let b = Region::new(PhysAddr::new(0x3000), 0x1000);
+
+        assert_eq!(a.end(), PhysAddr::new(0x3000));
+        assert!(a.contains(PhysAddr::new(0x2fff)));
+        assert!(!a.contains(a.end()));
+        assert!(!a.overlaps(&b));
+        assert!(a.overlaps(&Region::new(PhysAddr::new(0x2000), 0x2000)));
+    }
+
+    proptest! {
+        #[test]
+        fn overlap_is_symmetric(a in region(), b in region()) {
+            prop_assert_eq!(a.overlaps(&b), b.overlaps(&a));
+        }
+
+        #[test]
+        fn overlap_means_common_address(a in region(), b in region()) {
+            let common = a.start.max(b.start);
+            prop_assert_eq!(a.overlaps(&b), a.contains(common) && b.contains(common));
+        }
+    }
+}
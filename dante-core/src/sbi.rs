@@ -0,0 +1,156 @@
+use core::fmt;
+
+pub type SbiResult<T> = Result<T, SbiError>;
+
+#[allow(non_upper_case_globals)]
+pub const SbiSuccess: isize = 0;
+
+#[inline]
+pub fn sbi_ret<T>(status: isize, value: T) -> SbiResult<T> {
+    if status == SbiSuccess {
+        Ok(value)
+    } else {
+        Err(SbiError::new(status))
+    }
+}
+
+/// State of a hart, as tracked by the SBI HSM extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+    Unknown(usize),
+}
+
+impl HartState {
+    #[inline]
+    pub fn new(n: usize) -> Self {
+        match n {
+            0 => HartState::Started,
+            1 => HartState::Stopped,
+            2 => HartState::StartPending,
+            3 => HartState::StopPending,
+            4 => HartState::Suspended,
+            5 => HartState::SuspendPending,
+            6 => HartState::ResumePending,
+            n => HartState::Unknown(n),
+        }
+    }
+}
+
+impl fmt::Display for HartState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            HartState::Started => "started",
+            HartState::Stopped => "stopped",
+            HartState::StartPending => "start pending",
+            HartState::StopPending => "stop pending",
+            HartState::Suspended => "suspended",
+            HartState::SuspendPending => "suspend pending",
+            HartState::ResumePending => "resume pending",
+            HartState::Unknown(n) => return write!(f, "unknown state {n}"),
+        };
+
+        msg.fmt(f)
+    }
+}
+
+/// Error codes returned by SBI calls
+///
+/// note: `SBI_SUCCESS` is not represented here since this is to be used as the
+/// error type in a `Result`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SbiError {
+    /// The SBI call failed
+    Failed,
+    /// The SBI call is not implemented or the functionality is not available
+    NotSupported,
+    /// An invalid parameter was passed
+    InvalidParameter,
+    /// The SBI implementation has denied execution of the call functionality
+    Denied,
+    /// An invalid address was passed
+    InvalidAddress,
+    /// The resource is already available
+    AlreadyAvailable,
+    /// The resource was previously started
+    AlreadyStarted,
+    /// The resource was previously stopped
+    AlreadyStopped,
+    /// Unknowne error
+    Unknown(isize),
+}
+
+impl SbiError {
+    #[inline]
+    pub fn new(n: isize) -> Self {
+        match n {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParameter,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            n => SbiError::Unknown(n),
+        }
+    }
+}
+
+impl fmt::Display for SbiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            SbiError::AlreadyAvailable => "resource is already available",
+            SbiError::Denied => "SBI implementation denied execution",
+            SbiError::Failed => "call to SBI failed",
+            SbiError::InvalidAddress => "invalid address passed",
+            SbiError::InvalidParameter => "invalid parameter passed",
+            SbiError::NotSupported => "SBI call not implemented or functionality not available",
+            SbiError::AlreadyStarted => "resource was already started",
+            SbiError::AlreadyStopped => "resource was already stopped",
+            SbiError::Unknown(n) => return write!(f, "unknown SBI error code: {n}"),
+        };
+
+        msg.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(SbiError::new(-1), SbiError::Failed);
+        assert_eq!(SbiError::new(-2), SbiError::NotSupported);
+        assert_eq!(SbiError::new(-8), SbiError::AlreadyStopped);
+        assert_eq!(SbiError::new(-42), SbiError::Unknown(-42));
+        assert_eq!(sbi_ret(0, 7), Ok(7));
+        assert_eq!(sbi_ret(-3, 7), Err(SbiError::InvalidParameter));
+    }
+
+    #[test]
+    fn hart_states() {
+        assert_eq!(HartState::new(0), HartState::Started);
+        assert_eq!(HartState::new(6), HartState::ResumePending);
+        assert_eq!(HartState::new(7).to_string(), "unknown state 7");
+    }
+
+    proptest! {
+        #[test]
+        fn only_standard_codes_are_known(n: isize) {
+            let known = !matches!(SbiError::new(n), SbiError::Unknown(_));
+
+            prop_assert_eq!(known, (-8..=-1).contains(&n));
+            prop_assert_eq!(sbi_ret(n, ()).is_ok(), n == SbiSuccess);
+        }
+    }
+}
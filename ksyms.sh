@@ -0,0 +1,19 @@
+#!/usr/bin/env bash
+# Embed the symbol table used by backtraces into the .ksyms section of a kernel (see ksyms.rs).
+# Rust symbols are demangled, and their hash removed.
+set -euo pipefail
+
+kernel=$1
+table=$(mktemp)
+trap 'rm -f "$table"' EXIT
+
+llvm-nm --numeric-sort --defined-only --demangle "$kernel" \
+    | sed -nE '/ \.L| \$[xd]/d; s/^([0-9a-f]+) [tTwW] /\1 /p' \
+    | sed -E -f "$(dirname "$0")/ksyms.sed" > "$table"
+size=$(llvm-size -A "$kernel" | awk '$1 == ".ksyms" { print $2 }')
+if [ "$(stat -c %s "$table")" -ge "$size" ]; then
+    echo "symbol table does not fit in .ksyms ($size bytes)" >&2
+    exit 1
+fi
+truncate -s "$size" "$table"
+llvm-objcopy --update-section .ksyms="$table" "$kernel"
@@ -60,23 +60,39 @@ SECTIONS
     HIDDEN(_KERNEL_VA_CODE_OFFSET = _KERNEL_VIRTUAL_CODE_START - _boot_end);
     . = _KERNEL_VIRTUAL_CODE_START; /* Set the location counter to the start of the kernel virtual code. */
 
-    /* Define the .text section for executable code. It uses the calculated VA offset for its physical address (AT()). */
-    .text : AT(ADDR(.text) - _KERNEL_VA_CODE_OFFSET) {
+    /* Define the .text section for executable code. It uses the calculated VA offset for its physical address (AT()).
+       The .text, .rodata and .data sections start on a page boundary so that they can be mapped with different
+       permissions. Everything from _stext to _srodata is mapped RX, from _srodata to _sdata R, and from _sdata
+       to _ekernel RW. */
+    .text ALIGN(4096) : AT(ADDR(.text) - _KERNEL_VA_CODE_OFFSET) {
+        _stext = .; /* Mark the start of the .text section. */
         *(.text.abort); /* Include abort handler code. */
         *(text .text.*) /* Include all other .text sections. */
+        _etext = .; /* Mark the end of the .text section. */
     }
 
-    /* Define the .rodata (read-only data) section, aligning it to 4 bytes. */
-    .rodata ALIGN(4) : AT(ADDR(.rodata) - _KERNEL_VA_CODE_OFFSET) {
+    /* Define the .rodata (read-only data) section, aligning it to a page. */
+    .rodata ALIGN(4096) : AT(ADDR(.rodata) - _KERNEL_VA_CODE_OFFSET) {
+        _srodata = .; /* Mark the start of the .rodata section. */
         *(.srodata .srodata.*); /* Include small read-only data. */
         *(.rodata .rodata.*); /* Include all other read-only data. */
 
         /* Align the end of the .rodata section to 4 bytes. */
         . = ALIGN(4);
+        _erodata = .; /* Mark the end of the .rodata section. */
     }
 
-    /* Define the .data section for initialized data, aligning it to 8 bytes. */
-    .data ALIGN(8) : AT(ADDR(.data) - _KERNEL_VA_CODE_OFFSET) {
+    /* Define the .ksyms section holding the symbol table used for backtraces. Its space is reserved by
+       ksyms.rs and filled after linking by `just symbols`, so it must be an output section of its own. It
+       is mapped read-only with .rodata. */
+    .ksyms ALIGN(8) : AT(ADDR(.ksyms) - _KERNEL_VA_CODE_OFFSET) {
+        _sksyms = .; /* Mark the start of the symbol table. */
+        KEEP(*(.ksyms));
+        _eksyms = .; /* Mark the end of the symbol table. */
+    }
+
+    /* Define the .data section for initialized data, aligning it to a page. */
+    .data ALIGN(4096) : AT(ADDR(.data) - _KERNEL_VA_CODE_OFFSET) {
         _sidata = LOADADDR(.data); /* Store the load address of the .data section. */
         _sdata = .; /* Mark the start of the .data section. */
         PROVIDE(__global_pointer$ = . + 0x800); /* Set the global pointer to an offset within the .data section. */
@@ -93,4 +109,8 @@ SECTIONS
         . = ALIGN(8); /* Align the end of the .bss section to 8 bytes. */
         _ebss = .; /* Mark the end of the .bss section. */
     }
+
+    /* Mark the end of the kernel image, rounded up to a page. */
+    . = ALIGN(4096);
+    _ekernel = .;
 }
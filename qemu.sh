@@ -0,0 +1,10 @@
+#!/usr/bin/env bash
+# Cargo runner: embed the symbols of the kernel given by cargo, then boot it in QEMU.
+# Used by `cargo run` and `cargo test`, so that backtraces are symbolized.
+set -euo pipefail
+
+kernel=$1
+shift
+
+"$(dirname "$0")/ksyms.sh" "$kernel"
+exec qemu-system-riscv64 -M virt -m 2G -nographic -kernel "$kernel" "$@"
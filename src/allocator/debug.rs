@@ -0,0 +1,484 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::mem::{align_of, size_of};
+use core::ptr::{self, NonNull};
+
+use log::info;
+use spinning_top::Spinlock as SpinLock;
+
+use crate::arch::without_interrupts;
+use crate::backtrace::Backtrace;
+use crate::ksyms;
+use crate::memory::align_up;
+
+use super::{size_class_index, SIZE_CLASSES};
+
+/// Bytes of redzone after an allocation, there are at least as many before it
+const REDZONE: usize = 16;
+const REDZONE_BYTE: u8 = 0xfd;
+/// Fills new allocations, to expose reads of uninitialized memory
+const ALLOC_POISON: u8 = 0xa5;
+/// Fills freed allocations, to expose use after free
+const FREE_POISON: u8 = 0xdd;
+
+const LIVE_MAGIC: u64 = 0x6865_6170_6c69_7665;
+const FREED_MAGIC: u64 = 0x6865_6170_6672_6565;
+
+/// Return addresses recorded for every allocation
+const CALLER_DEPTH: usize = 6;
+const MAX_CALL_SITES: usize = 256;
+/// Size classes, and the large allocations
+const CLASSES: usize = SIZE_CLASSES.len() + 1;
+
+/// Allocations listed at most by [`DebugAllocator::report`]
+const REPORTED_ALLOCATIONS: usize = 32;
+const REPORTED_CALL_SITES: usize = 16;
+
+/// Prefixes of the functions skipped to find the caller of an allocation
+const ALLOCATION_FUNCTIONS: [&str; 8] = [
+    "alloc::",
+    "<alloc::",
+    "core::",
+    "<core::",
+    "dante::allocator::",
+    "<dante::allocator::",
+    "__rust",
+    "__rg_",
+];
+
+/// Header at the start of every allocation, followed by a redzone
+#[repr(C)]
+struct Header {
+    prev: Option<NonNull<Header>>,
+    next: Option<NonNull<Header>>,
+    layout: Layout,
+    site: usize,
+    /// Last, so that the free list of the inner allocator does not overwrite
+    /// it and double frees are detected
+    magic: u64,
+}
+
+/// Return addresses of an allocation
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Callers([usize; CALLER_DEPTH]);
+
+impl Callers {
+    const EMPTY: Callers = Callers([0; CALLER_DEPTH]);
+
+    #[inline(always)]
+    fn current() -> Self {
+        let mut callers = Self::EMPTY;
+        for (slot, ra) in callers.0.iter_mut().zip(Backtrace::current()) {
+            *slot = ra;
+        }
+        callers
+    }
+
+    /// The first caller outside of the allocation functions
+    fn site(&self) -> Option<ksyms::Location> {
+        let mut locations = self
+            .0
+            .iter()
+            .filter_map(|&ra| ksyms::lookup(ra.checked_sub(1)?));
+        let first = locations.clone().next();
+
+        locations
+            .find(|location| {
+                !ALLOCATION_FUNCTIONS
+                    .iter()
+                    .any(|prefix| location.symbol.name.starts_with(prefix))
+            })
+            .or(first)
+    }
+}
+
+impl fmt::Display for Callers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.site() {
+            Some(location) => write!(f, "{location}"),
+            None => write!(f, "{:#x}", self.0[0]),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CallSite {
+    callers: Callers,
+    allocs: usize,
+    live: usize,
+    live_bytes: usize,
+}
+
+#[derive(Clone, Copy)]
+struct ClassCounters {
+    allocs: usize,
+    frees: usize,
+}
+
+struct State {
+    live: Option<NonNull<Header>>,
+    live_count: usize,
+    live_bytes: usize,
+    peak_bytes: usize,
+    classes: [ClassCounters; CLASSES],
+    sites: [CallSite; MAX_CALL_SITES],
+    /// Allocations whose call site did not fit in `sites`
+    untracked: usize,
+}
+
+// SAFETY: the state is only accessed with its lock held
+unsafe impl Send for State {}
+
+/// Wrapper of the global allocator which checks and records every allocation.
+///
+/// Allocations are surrounded by redzones which are checked when they are
+/// freed, and are filled with poison when allocated and freed. Each live
+/// allocation is linked in a list along with its call site.
+pub struct DebugAllocator<A> {
+    inner: A,
+    state: SpinLock<State>,
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            state: SpinLock::new(State {
+                live: None,
+                live_count: 0,
+                live_bytes: 0,
+                peak_bytes: 0,
+                classes: [ClassCounters {
+                    allocs: 0,
+                    frees: 0,
+                }; CLASSES],
+                sites: [CallSite {
+                    callers: Callers::EMPTY,
+                    allocs: 0,
+                    live: 0,
+                    live_bytes: 0,
+                }; MAX_CALL_SITES],
+                untracked: 0,
+            }),
+        }
+    }
+
+    pub fn live_bytes(&self) -> usize {
+        without_interrupts(|| self.state.lock().live_bytes)
+    }
+
+    /// Log the allocation counters, the call sites with the most live memory
+    /// and the first live allocations
+    pub fn report(&self) {
+        without_interrupts(|| {
+            let state = self.state.lock();
+
+            info!(
+                "  live: {} allocations, {} bytes, peak {} bytes",
+                state.live_count, state.live_bytes, state.peak_bytes
+            );
+
+            for (index, counters) in state.classes.iter().enumerate() {
+                let name = SIZE_CLASSES
+                    .get(index)
+                    .map_or("large", |cache| cache.name());
+                info!(
+                    "  {name:<16} {:>8} allocs {:>8} frees",
+                    counters.allocs, counters.frees
+                );
+            }
+
+            info!("  call sites by live bytes:");
+            let mut previous = (usize::MAX, usize::MAX);
+            for _ in 0..REPORTED_CALL_SITES {
+                let Some((index, site)) = state
+                    .sites
+                    .iter()
+                    .enumerate()
+                    .filter(|&(index, site)| site.live > 0 && (site.live_bytes, index) < previous)
+                    .max_by_key(|&(index, site)| (site.live_bytes, index))
+                else {
+                    break;
+                };
+
+                info!(
+                    "    {:>8} bytes {:>6} live {:>8} allocs  {}",
+                    site.live_bytes, site.live, site.allocs, site.callers
+                );
+                previous = (site.live_bytes, index);
+            }
+            if state.untracked > 0 {
+                info!(
+                    "    {} allocations from untracked call sites",
+                    state.untracked
+                );
+            }
+
+            info!("  live allocations:");
+            let mut next = state.live;
+            let mut listed = 0;
+            while let Some(header) = next {
+                let header = unsafe { header.as_ref() };
+                if listed == REPORTED_ALLOCATIONS {
+                    info!("    ...");
+                    break;
+                }
+
+                let object = object_of(header);
+                let callers = state
+                    .sites
+                    .get(header.site)
+                    .map_or(Callers::EMPTY, |site| site.callers);
+                info!(
+                    "    {object:#018x} {:>8} bytes  {callers}",
+                    header.layout.size()
+                );
+                listed += 1;
+                next = header.next;
+            }
+        })
+    }
+}
+
+/// Layout of the allocation holding an object of `layout`, and the offset
+/// of the object in it
+fn outer_layout(layout: Layout) -> (Layout, usize) {
+    let align = layout.align().max(align_of::<Header>());
+    let offset = align_up(size_of::<Header>() + REDZONE, align);
+    let size = offset + layout.size() + REDZONE;
+
+    (Layout::from_size_align(size, align).unwrap(), offset)
+}
+
+/// Index of the counters of an object of `layout`, by the size class which
+/// its outer allocation comes from
+fn class_index(layout: Layout) -> usize {
+    size_class_index(outer_layout(layout).0)
+}
+
+fn object_of(header: &Header) -> usize {
+    header as *const Header as usize + outer_layout(header.layout).1
+}
+
+/// Whether the `len` bytes at `start` are all `byte`
+unsafe fn is_filled(start: *const u8, len: usize, byte: u8) -> bool {
+    core::slice::from_raw_parts(start, len)
+        .iter()
+        .all(|&b| b == byte)
+}
+
+impl State {
+    fn insert(&mut self, mut header_ptr: NonNull<Header>, callers: Callers) {
+        let header = unsafe { header_ptr.as_mut() };
+        let size = header.layout.size();
+
+        self.live_count += 1;
+        self.live_bytes += size;
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+        self.classes[class_index(header.layout)].allocs += 1;
+
+        header.site = self.site_index(callers).unwrap_or_else(|| {
+            self.untracked += 1;
+            MAX_CALL_SITES
+        });
+        if let Some(site) = self.sites.get_mut(header.site) {
+            site.allocs += 1;
+            site.live += 1;
+            site.live_bytes += size;
+        }
+
+        header.prev = None;
+        header.next = self.live;
+        if let Some(mut next) = self.live {
+            unsafe { next.as_mut().prev = Some(header_ptr) };
+        }
+        self.live = Some(header_ptr);
+    }
+
+    /// Whether the neighbours of `header` in the live list point back to it
+    fn is_linked(&self, header_ptr: NonNull<Header>) -> bool {
+        let header = unsafe { header_ptr.as_ref() };
+        let prev_linked = match header.prev {
+            Some(prev) => unsafe { prev.as_ref() }.next == Some(header_ptr),
+            None => self.live == Some(header_ptr),
+        };
+        let next_linked = header
+            .next
+            .is_none_or(|next| unsafe { next.as_ref() }.prev == Some(header_ptr));
+
+        prev_linked && next_linked
+    }
+
+    fn remove(&mut self, header: &Header) {
+        let size = header.layout.size();
+
+        self.live_count -= 1;
+        self.live_bytes -= size;
+        self.classes[class_index(header.layout)].frees += 1;
+
+        if let Some(site) = self.sites.get_mut(header.site) {
+            site.live -= 1;
+            site.live_bytes -= size;
+        } else {
+            self.untracked -= 1;
+        }
+
+        match header.prev {
+            Some(mut prev) => unsafe { prev.as_mut().next = header.next },
+            None => self.live = header.next,
+        }
+        if let Some(mut next) = header.next {
+            unsafe { next.as_mut().prev = header.prev };
+        }
+    }
+
+    /// Index of the call site of `callers` in `sites`, added if needed
+    fn site_index(&mut self, callers: Callers) -> Option<usize> {
+        let hash = callers
+            .0
+            .iter()
+            .fold(0usize, |hash, &ra| hash.rotate_left(5) ^ ra);
+
+        (0..MAX_CALL_SITES)
+            .map(|probe| hash.wrapping_add(probe) % MAX_CALL_SITES)
+            .find(|&index| {
+                let site = &mut self.sites[index];
+                if site.allocs == 0 {
+                    site.callers = callers;
+                }
+                site.callers == callers
+            })
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let callers = Callers::current();
+        let (outer, offset) = outer_layout(layout);
+
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+
+        let object = base.add(offset);
+        ptr::write_bytes(base, REDZONE_BYTE, offset);
+        ptr::write_bytes(object, ALLOC_POISON, layout.size());
+        ptr::write_bytes(object.add(layout.size()), REDZONE_BYTE, REDZONE);
+
+        let header = base.cast::<Header>();
+        header.write(Header {
+            prev: None,
+            next: None,
+            layout,
+            site: 0,
+            magic: LIVE_MAGIC,
+        });
+
+        without_interrupts(|| {
+            self.state
+                .lock()
+                .insert(NonNull::new_unchecked(header), callers)
+        });
+
+        object
+    }
+
+    unsafe fn dealloc(&self, object: *mut u8, layout: Layout) {
+        let (outer, offset) = outer_layout(layout);
+        let base = object.sub(offset);
+        let header = &mut *base.cast::<Header>();
+
+        match header.magic {
+            LIVE_MAGIC => {}
+            FREED_MAGIC => panic!("double free of {object:p} ({layout:?})"),
+            _ => panic!("free of {object:p} which is not a live allocation, or whose header was overwritten"),
+        }
+
+        let site = without_interrupts(|| {
+            let state = self.state.lock();
+            state.sites.get(header.site).map(|site| site.callers)
+        });
+        let site = site.unwrap_or(Callers::EMPTY);
+
+        assert_eq!(
+            header.layout, layout,
+            "{object:p} freed with another layout than allocated at {site}"
+        );
+
+        let front = base.add(size_of::<Header>());
+        let front_len = offset - size_of::<Header>();
+        assert!(
+            is_filled(front, front_len, REDZONE_BYTE),
+            "heap underflow before {object:p} ({layout:?}) allocated at {site}"
+        );
+        assert!(
+            is_filled(object.add(layout.size()), REDZONE, REDZONE_BYTE),
+            "heap overflow after {object:p} ({layout:?}) allocated at {site}"
+        );
+
+        // Only unlink the header once it is known to be intact, the list
+        // pointers of a corrupted one would be written through
+        let linked = without_interrupts(|| {
+            let mut state = self.state.lock();
+            let linked = state.is_linked(NonNull::from(&*header));
+            if linked {
+                state.remove(header);
+            }
+            linked
+        });
+        assert!(
+            linked,
+            "list of live allocations corrupted around {object:p} ({layout:?}) allocated at {site}"
+        );
+
+        header.magic = FREED_MAGIC;
+        ptr::write_bytes(object, FREE_POISON, layout.size());
+
+        self.inner.dealloc(base, outer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::ALLOCATOR;
+    use crate::prelude::*;
+
+    #[test_case]
+    fn allocations_are_poisoned() {
+        let mut vec: Vec<u8> = Vec::with_capacity(64);
+        let spare = vec.spare_capacity_mut();
+        assert!(spare
+            .iter()
+            .all(|byte| unsafe { byte.assume_init() } == ALLOC_POISON));
+
+        vec.push(1);
+        let ptr = vec.as_ptr();
+        drop(vec);
+
+        // Reading freed memory is undefined behavior, but it is what this debug mode is for
+        assert_eq!(unsafe { ptr.add(32).read_volatile() }, FREE_POISON);
+    }
+
+    #[test_case]
+    fn live_bytes_are_counted() {
+        let before = ALLOCATOR.live_bytes();
+        let value = Box::new([0u8; 100]);
+
+        assert_eq!(ALLOCATOR.live_bytes(), before + 100);
+        drop(value);
+        assert_eq!(ALLOCATOR.live_bytes(), before);
+    }
+
+    #[test_case]
+    fn redzones_surround_allocations() {
+        let value = Box::new(0u64);
+        let object = &*value as *const u64 as *const u8;
+
+        unsafe {
+            assert!(is_filled(object.sub(REDZONE), REDZONE, REDZONE_BYTE));
+            assert!(is_filled(object.add(8), REDZONE, REDZONE_BYTE));
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use core::alloc::Layout;
+use core::fmt;
+use core::ptr::NonNull;
+
+use linked_list_allocator::Heap;
+use spinning_top::Spinlock as SpinLock;
+
+use crate::arch::{without_interrupts, PAGE_SIZE};
+use crate::memory::{align_up, alloc_frames, free_frames, VirtAddr, HEAP_MAX_SIZE, HEAP_START};
+use crate::page_table::{KERNEL_PAGE_TABLE, PTE_READ, PTE_WRITE};
+
+/// The heap is grown by at least this much, to amortize the page table updates
+const HEAP_GROWTH: usize = 1024 * 1024;
+
+static HEAP: SpinLock<GrowableHeap> = SpinLock::new(GrowableHeap {
+    heap: Heap::empty(),
+    mapped: 0,
+});
+
+/// Held while the heap grows, so that a single hart maps frames after it.
+///
+/// Taken before [`KERNEL_PAGE_TABLE`] and [`HEAP`]. `HEAP` is never held while
+/// the page table is locked: mapping frames may need more page tables.
+static GROWING: SpinLock<()> = SpinLock::new(());
+
+/// Heap of the large allocations, at [`HEAP_START`].
+///
+/// Only the first `mapped` bytes of the range are backed by frames, the heap
+/// grows by mapping more frames right after them. Frames are never unmapped.
+struct GrowableHeap {
+    heap: Heap,
+    mapped: usize,
+}
+
+/// Usage of the heap
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// Bytes of the heap range backed by frames
+    pub mapped: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes mapped, {} used, {} free",
+            self.mapped, self.used, self.free
+        )
+    }
+}
+
+impl GrowableHeap {
+    /// Add the `grown` bytes mapped right after the heap to it
+    fn extend(&mut self, grown: usize) {
+        if grown == 0 {
+            return;
+        }
+
+        let start = HEAP_START + self.mapped;
+        unsafe {
+            if self.mapped == 0 {
+                self.heap.init(start as *mut u8, grown);
+            } else {
+                self.heap.extend(grown);
+            }
+        }
+
+        self.mapped += grown;
+    }
+
+    /// Size of the largest allocation which currently succeeds without growing
+    /// the heap, found by trying allocations. Only meant for error reports.
+    fn largest_free_block(&mut self) -> usize {
+        let (mut fits, mut too_large) = (0, self.heap.free() + 1);
+
+        while too_large - fits > 1 {
+            let size = fits + (too_large - fits) / 2;
+            let layout = Layout::from_size_align(size, 1).unwrap();
+
+            match self.heap.allocate_first_fit(layout) {
+                Ok(ptr) => {
+                    unsafe { self.heap.deallocate(ptr, layout) };
+                    fits = size;
+                }
+                Err(()) => too_large = size,
+            }
+        }
+
+        fits
+    }
+}
+
+pub fn alloc(layout: Layout) -> Option<NonNull<u8>> {
+    without_interrupts(|| {
+        if let Ok(ptr) = HEAP.lock().heap.allocate_first_fit(layout) {
+            return Some(ptr);
+        }
+
+        let _growing = GROWING.lock();
+        let mapped = {
+            let mut heap = HEAP.lock();
+            // Another hart may have grown the heap in the meantime
+            if let Ok(ptr) = heap.heap.allocate_first_fit(layout) {
+                return Some(ptr);
+            }
+            heap.mapped
+        };
+
+        // The free space at the top of the heap may not be usable for an
+        // aligned allocation, grow enough for the worst case
+        let grown = grow(mapped, layout.size() + layout.align());
+
+        let mut heap = HEAP.lock();
+        heap.extend(grown);
+        heap.heap.allocate_first_fit(layout).ok()
+    })
+}
+
+/// Map at least `size` bytes after the first `mapped` bytes of the heap, or
+/// as much as possible, returning how many were mapped
+fn grow(mapped: usize, size: usize) -> usize {
+    let size = align_up(size.max(HEAP_GROWTH), PAGE_SIZE).min(HEAP_MAX_SIZE - mapped);
+    let start = HEAP_START + mapped;
+    let mut grown = 0;
+
+    let mut root_pt = KERNEL_PAGE_TABLE.lock();
+    while grown < size {
+        let Some(frame) = alloc_frames(0) else {
+            break;
+        };
+
+        let virt = VirtAddr::new(start + grown);
+        if root_pt
+            .map(virt, frame, PAGE_SIZE, PTE_READ | PTE_WRITE)
+            .is_err()
+        {
+            // No frame left for the page tables
+            free_frames(frame, 0);
+            break;
+        }
+        grown += PAGE_SIZE;
+    }
+
+    grown
+}
+
+/// SAFETY: `ptr` must come from [`alloc`] with the same `layout`
+pub unsafe fn dealloc(ptr: NonNull<u8>, layout: Layout) {
+    without_interrupts(|| HEAP.lock().heap.deallocate(ptr, layout))
+}
+
+pub fn stats() -> HeapStats {
+    without_interrupts(|| {
+        let heap = HEAP.lock();
+        HeapStats {
+            mapped: heap.mapped,
+            used: heap.heap.used(),
+            free: heap.heap.free(),
+        }
+    })
+}
+
+pub fn largest_free_block() -> usize {
+    without_interrupts(|| HEAP.lock().largest_free_block())
+}
@@ -0,0 +1,247 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+use log::info;
+
+use crate::debug_println;
+
+#[cfg(feature = "heap-debug")]
+mod debug;
+mod heap;
+mod slab;
+
+#[cfg(feature = "heap-debug")]
+pub use debug::DebugAllocator;
+pub use heap::{largest_free_block, HeapStats};
+pub use slab::{Cache, CacheStats, ObjectCache};
+
+/// Caches of the general purpose allocations, by powers of two from 16 bytes.
+/// Larger allocations go to the heap.
+static SIZE_CLASSES: [Cache; 8] = [
+    Cache::new("kmalloc-16", 16, 16),
+    Cache::new("kmalloc-32", 32, 32),
+    Cache::new("kmalloc-64", 64, 64),
+    Cache::new("kmalloc-128", 128, 128),
+    Cache::new("kmalloc-256", 256, 256),
+    Cache::new("kmalloc-512", 512, 512),
+    Cache::new("kmalloc-1024", 1024, 1024),
+    Cache::new("kmalloc-2048", 2048, 2048),
+];
+
+const MIN_SIZE_CLASS_SHIFT: u32 = 4;
+
+#[cfg(not(feature = "heap-debug"))]
+#[global_allocator]
+static ALLOCATOR: SlabAllocator = SlabAllocator;
+
+/// With the `heap-debug` feature, every allocation is checked and recorded
+#[cfg(feature = "heap-debug")]
+#[global_allocator]
+static ALLOCATOR: DebugAllocator<SlabAllocator> = DebugAllocator::new(SlabAllocator);
+
+pub struct SlabAllocator;
+
+/// Index of the size class of `layout` in [`SIZE_CLASSES`], or its length for
+/// large allocations
+fn size_class_index(layout: Layout) -> usize {
+    let size = layout.size().max(layout.align()).next_power_of_two();
+    let index = size.trailing_zeros().saturating_sub(MIN_SIZE_CLASS_SHIFT) as usize;
+    index.min(SIZE_CLASSES.len())
+}
+
+/// The size class holding allocations of `layout`, `None` for large allocations
+fn size_class(layout: Layout) -> Option<&'static Cache> {
+    SIZE_CLASSES.get(size_class_index(layout))
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match size_class(layout) {
+            Some(cache) => cache.alloc().map_or(ptr::null_mut(), NonNull::as_ptr),
+            None => heap::alloc(layout).map_or(ptr::null_mut(), NonNull::as_ptr),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match size_class(layout) {
+            Some(cache) => cache.free(NonNull::new_unchecked(ptr)),
+            None => heap::dealloc(NonNull::new_unchecked(ptr), layout),
+        }
+    }
+}
+
+/// Usage of the general purpose size classes
+pub fn size_class_stats() -> impl Iterator<Item = CacheStats> {
+    SIZE_CLASSES.iter().map(Cache::stats)
+}
+
+pub fn heap_stats() -> HeapStats {
+    heap::stats()
+}
+
+/// Log the usage of the heap and of the size classes, and with the
+/// `heap-debug` feature the live allocations and their call sites
+pub fn heap_report() {
+    let heap = heap_stats();
+    let largest = largest_free_block();
+    // Share of the free heap memory which cannot serve the largest allocation
+    let fragmentation = (heap.free - largest) * 100 / heap.free.max(1);
+
+    info!("heap: {heap}, largest free block {largest} bytes ({fragmentation}% fragmented)");
+    for stats in size_class_stats() {
+        info!("  {stats}");
+    }
+
+    #[cfg(feature = "heap-debug")]
+    ALLOCATOR.report();
+}
+
+/// Report the failed allocation and the state of the allocator, then panic.
+///
+/// Stable toolchains have no `alloc_error_handler`, their default handler
+/// panics without the report.
+#[cfg_attr(nightly, alloc_error_handler)]
+fn alloc_error(layout: Layout) -> ! {
+    debug_println!("\n==== OUT OF MEMORY ====");
+    debug_println!("Failed allocation: {layout:?}");
+    debug_println!("Heap: {}", heap_stats());
+    debug_println!("Largest free block: {} bytes", largest_free_block());
+    for stats in size_class_stats() {
+        debug_println!("  {stats}");
+    }
+
+    panic!(
+        "memory allocation of {} bytes aligned on {} failed",
+        layout.size(),
+        layout.align()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::PAGE_SIZE;
+    use crate::memory::HEAP_START;
+    use crate::prelude::*;
+
+    #[test_case]
+    fn box_value() {
+        let value = Box::new(41);
+        assert_eq!(*value, 41);
+    }
+
+    #[test_case]
+    fn large_vec() {
+        let vec: Vec<usize> = (0..500).collect();
+        assert_eq!(vec.iter().sum::<usize>(), 499 * 500 / 2);
+    }
+
+    #[test_case]
+    fn reference_counting() {
+        let reference_counted = Rc::new(vec![1, 2, 3]);
+        let cloned_reference = reference_counted.clone();
+        assert_eq!(Rc::strong_count(&cloned_reference), 2);
+
+        core::mem::drop(reference_counted);
+        assert_eq!(Rc::strong_count(&cloned_reference), 1);
+    }
+
+    #[test_case]
+    fn freed_memory_is_reused() {
+        for i in 0..10_000 {
+            let value = Box::new(i);
+            assert_eq!(*value, i);
+        }
+    }
+
+    #[test_case]
+    fn size_classes() {
+        let small = Box::new(0u8);
+        let medium = vec![0u64; 100];
+        let large = vec![0u8; 64 * 1024];
+
+        assert_eq!(&*small as *const u8 as usize % 16, 0);
+        assert_eq!(medium.as_ptr() as usize % 1024, 0);
+
+        let heap = HEAP_START..HEAP_START + heap_stats().mapped;
+        assert!(heap.contains(&(large.as_ptr() as usize)));
+    }
+
+    #[test_case]
+    fn heap_grows() {
+        let before = heap_stats().mapped;
+        let large = vec![1u8; before.max(PAGE_SIZE) + 1];
+
+        assert!(heap_stats().mapped > before);
+        assert_eq!(large.last(), Some(&1));
+    }
+
+    #[test_case]
+    fn over_aligned() {
+        #[repr(align(4096))]
+        struct Page([u8; 32]);
+
+        let page = Box::new(Page([1; 32]));
+        assert_eq!(&*page as *const Page as usize % 4096, 0);
+        assert_eq!(page.0[31], 1);
+    }
+
+    #[test_case]
+    fn slabs_are_released() {
+        let before = size_class_stats().map(|stats| stats.slabs).sum::<usize>();
+
+        let values: Vec<Box<[u8; 200]>> = (0..1000).map(|_| Box::new([0; 200])).collect();
+        drop(values);
+
+        let after = size_class_stats().map(|stats| stats.slabs).sum::<usize>();
+        // Empty slabs are kept only for the objects left in the magazines
+        assert!(after < before + 20, "{before} slabs before, {after} after");
+    }
+
+    #[test_case]
+    fn object_cache() {
+        static CACHE: ObjectCache<[u64; 3]> = ObjectCache::new("test");
+
+        let objects: Vec<_> = (0..100).map(|i| CACHE.alloc([i; 3]).unwrap()).collect();
+        assert_eq!(CACHE.stats().in_use, 100);
+        assert_eq!(CACHE.stats().object_size, 24);
+
+        for (i, object) in objects.into_iter().enumerate() {
+            assert_eq!(unsafe { object.as_ref() }, &[i as u64; 3]);
+            unsafe { CACHE.free(object) };
+        }
+
+        assert_eq!(CACHE.stats().in_use, 0);
+    }
+
+    #[test_case]
+    fn page_sized_objects_take_single_frames() {
+        #[repr(align(4096))]
+        struct Page([u8; PAGE_SIZE]);
+
+        static CACHE: ObjectCache<Page> = ObjectCache::new("test-page");
+
+        let objects: Vec<_> = (0..20)
+            .map(|_| CACHE.alloc(Page([7; PAGE_SIZE])).unwrap())
+            .collect();
+        let stats = CACHE.stats();
+        assert_eq!(stats.object_size, PAGE_SIZE);
+        // One object per block, there is no slab header to make room for
+        assert_eq!(stats.capacity, stats.slabs);
+
+        for object in objects {
+            assert_eq!(object.as_ptr() as usize % PAGE_SIZE, 0);
+            assert_eq!(unsafe { object.as_ref() }.0[PAGE_SIZE - 1], 7);
+            unsafe { CACHE.free(object) };
+        }
+
+        assert_eq!(CACHE.stats().in_use, 0);
+    }
+
+    #[test_case]
+    fn string() {
+        let mut string = String::from("It did not ");
+        string.push_str("crash");
+        assert_eq!(string, "It did not crash");
+    }
+}
@@ -0,0 +1,406 @@
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr::NonNull;
+
+use spinning_top::guard::SpinlockGuard;
+use spinning_top::Spinlock as SpinLock;
+
+use crate::arch::{without_interrupts, MAX_HARTS};
+use crate::hart;
+use crate::memory::{
+    align_down, align_up, alloc_frames, free_frames, order_size, PhysAddrExt, VirtAddr, VirtAddrExt,
+};
+
+/// Number of free objects each hart keeps at hand for every cache
+const MAGAZINE_SIZE: usize = 16;
+
+/// Slabs are grown up to this order until they hold [`MIN_SLAB_OBJECTS`]
+const MAX_SLAB_ORDER: usize = 4;
+const MIN_SLAB_OBJECTS: usize = 8;
+
+/// Header of a free object, linking it to the next free object of its slab
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Header stored at the end of every slab.
+///
+/// Slabs are blocks of the frame allocator, aligned on their size, so the
+/// slab of an object is found by rounding its address down. Objects which do
+/// not fit in a frame next to the header, such as page tables, have no slab:
+/// each is a whole block of the frame allocator.
+struct Slab {
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    free: Option<NonNull<FreeObject>>,
+    in_use: usize,
+}
+
+/// Free objects owned by a hart, which it allocates and frees without
+/// touching the shared slabs
+struct Magazine {
+    objects: [Option<NonNull<u8>>; MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const EMPTY: Magazine = Magazine {
+        objects: [None; MAGAZINE_SIZE],
+        len: 0,
+    };
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        self.len = self.len.checked_sub(1)?;
+        self.objects[self.len].take()
+    }
+
+    fn push(&mut self, object: NonNull<u8>) {
+        self.objects[self.len] = Some(object);
+        self.len += 1;
+    }
+}
+
+/// The slabs of a cache, shared by all the harts
+struct Depot {
+    /// Slabs with at least one free object
+    partial: Option<NonNull<Slab>>,
+    slabs: usize,
+    /// Objects taken out of the slabs, including those sitting in magazines
+    taken: usize,
+}
+
+// SAFETY: the magazines and the depot are only accessed with their lock held
+unsafe impl Send for Magazine {}
+unsafe impl Send for Depot {}
+
+/// Cache of objects of a single size, carved out of slabs from the frame allocator.
+///
+/// Each hart keeps a magazine of free objects, so that most allocations and
+/// frees only take the uncontended lock of the current hart. Magazines are
+/// refilled from and flushed to the slabs by halves.
+pub struct Cache {
+    name: &'static str,
+    object_size: usize,
+    order: usize,
+    objects_per_slab: usize,
+    /// Each object is a block of `order`, without a slab header
+    whole_blocks: bool,
+    depot: SpinLock<Depot>,
+    magazines: [SpinLock<Magazine>; MAX_HARTS],
+}
+
+/// Usage of a [`Cache`]
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slabs: usize,
+    /// Objects which fit in the slabs
+    pub capacity: usize,
+    /// Objects currently allocated
+    pub in_use: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<16} {:>5} B  {:>6}/{:<6} objects  {:>4} slabs",
+            self.name, self.object_size, self.in_use, self.capacity, self.slabs
+        )
+    }
+}
+
+impl Cache {
+    /// A cache of objects of `size` bytes aligned on `align`, no larger than a
+    /// slab of [`MAX_SLAB_ORDER`]
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = if align > align_of::<FreeObject>() {
+            align
+        } else {
+            align_of::<FreeObject>()
+        };
+        let size = if size > size_of::<FreeObject>() {
+            size
+        } else {
+            size_of::<FreeObject>()
+        };
+        let object_size = align_up(size, align);
+
+        let whole_blocks = Self::capacity(object_size, 0) == 0;
+
+        let mut order = 0;
+        if whole_blocks {
+            while order_size(order) < object_size {
+                order += 1;
+            }
+        } else {
+            while order < MAX_SLAB_ORDER && Self::capacity(object_size, order) < MIN_SLAB_OBJECTS {
+                order += 1;
+            }
+        }
+        assert!(order <= MAX_SLAB_ORDER, "object too large for a slab");
+
+        let objects_per_slab = if whole_blocks {
+            1
+        } else {
+            Self::capacity(object_size, order)
+        };
+
+        Self {
+            name,
+            object_size,
+            order,
+            objects_per_slab,
+            whole_blocks,
+            depot: SpinLock::new(Depot {
+                partial: None,
+                slabs: 0,
+                taken: 0,
+            }),
+            magazines: [const { SpinLock::new(Magazine::EMPTY) }; MAX_HARTS],
+        }
+    }
+
+    /// Number of objects fitting in a slab of `order`, before its header
+    const fn capacity(object_size: usize, order: usize) -> usize {
+        Self::header_offset(order) / object_size
+    }
+
+    const fn header_offset(order: usize) -> usize {
+        align_down(order_size(order) - size_of::<Slab>(), align_of::<Slab>())
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    pub fn alloc(&self) -> Option<NonNull<u8>> {
+        without_interrupts(|| {
+            let Some(mut magazine) = self.magazine() else {
+                return self.depot.lock().alloc(self);
+            };
+
+            if magazine.len == 0 {
+                let mut depot = self.depot.lock();
+                while magazine.len < MAGAZINE_SIZE / 2 {
+                    match depot.alloc(self) {
+                        Some(object) => magazine.push(object),
+                        None => break,
+                    }
+                }
+            }
+
+            magazine.pop()
+        })
+    }
+
+    /// SAFETY: `object` must come from [`Cache::alloc`] on this cache, and
+    /// not be used anymore
+    pub unsafe fn free(&self, object: NonNull<u8>) {
+        without_interrupts(|| {
+            let Some(mut magazine) = self.magazine() else {
+                return self.depot.lock().free(self, object);
+            };
+
+            if magazine.len == MAGAZINE_SIZE {
+                let mut depot = self.depot.lock();
+                while magazine.len > MAGAZINE_SIZE / 2 {
+                    depot.free(self, magazine.pop().unwrap());
+                }
+            }
+
+            magazine.push(object);
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        without_interrupts(|| {
+            let (slabs, taken) = {
+                let depot = self.depot.lock();
+                (depot.slabs, depot.taken)
+            };
+            let cached: usize = self.magazines.iter().map(|m| m.lock().len).sum();
+
+            CacheStats {
+                name: self.name,
+                object_size: self.object_size,
+                slabs,
+                capacity: slabs * self.objects_per_slab,
+                in_use: taken.saturating_sub(cached),
+            }
+        })
+    }
+
+    /// The magazine of the current hart, `None` before the harts are known
+    fn magazine(&self) -> Option<SpinlockGuard<'_, Magazine>> {
+        let magazine = self.magazines.get(hart::try_id()?)?;
+        Some(magazine.lock())
+    }
+
+    fn slab_of(&self, object: NonNull<u8>) -> NonNull<Slab> {
+        let base = align_down(object.as_ptr() as usize, order_size(self.order));
+        NonNull::new((base + Self::header_offset(self.order)) as *mut Slab).unwrap()
+    }
+}
+
+impl Depot {
+    fn alloc(&mut self, cache: &Cache) -> Option<NonNull<u8>> {
+        if cache.whole_blocks {
+            let block = alloc_frames(cache.order)?.to_virt().unwrap();
+            self.slabs += 1;
+            self.taken += 1;
+            return NonNull::new(block.as_mut_ptr());
+        }
+
+        let slab_ptr = match self.partial {
+            Some(slab) => slab,
+            None => self.grow(cache)?,
+        };
+        let slab = unsafe { &mut *slab_ptr.as_ptr() };
+
+        let object = slab.free.unwrap();
+        slab.free = unsafe { object.as_ref().next };
+        slab.in_use += 1;
+        self.taken += 1;
+
+        if slab.free.is_none() {
+            self.unlink(slab_ptr);
+        }
+
+        Some(object.cast())
+    }
+
+    fn free(&mut self, cache: &Cache, object: NonNull<u8>) {
+        if cache.whole_blocks {
+            let block = VirtAddr::new(object.as_ptr() as usize);
+            free_frames(block.to_phys().unwrap(), cache.order);
+            self.slabs -= 1;
+            self.taken -= 1;
+            return;
+        }
+
+        let slab_ptr = cache.slab_of(object);
+        let slab = unsafe { &mut *slab_ptr.as_ptr() };
+        let was_full = slab.free.is_none();
+
+        let object = object.cast::<FreeObject>();
+        unsafe { object.as_ptr().write(FreeObject { next: slab.free }) };
+        slab.free = Some(object);
+        slab.in_use -= 1;
+        self.taken -= 1;
+
+        if was_full {
+            self.link(slab_ptr);
+        }
+
+        // Keep a single empty slab around, so that an object going back and
+        // forth does not allocate and free a slab every time
+        if slab.in_use == 0 && (slab.prev.is_some() || slab.next.is_some()) {
+            self.unlink(slab_ptr);
+            self.slabs -= 1;
+
+            let base = VirtAddr::new(align_down(
+                slab_ptr.as_ptr() as usize,
+                order_size(cache.order),
+            ));
+            free_frames(base.to_phys().unwrap(), cache.order);
+        }
+    }
+
+    /// Add a new slab to the partial list
+    fn grow(&mut self, cache: &Cache) -> Option<NonNull<Slab>> {
+        let base = alloc_frames(cache.order)?.to_virt().unwrap().as_usize();
+        let slab_ptr =
+            NonNull::new((base + Cache::header_offset(cache.order)) as *mut Slab).unwrap();
+
+        let mut free = None;
+        for index in (0..cache.objects_per_slab).rev() {
+            let object = (base + index * cache.object_size) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free }) };
+            free = NonNull::new(object);
+        }
+
+        unsafe {
+            slab_ptr.as_ptr().write(Slab {
+                prev: None,
+                next: None,
+                free,
+                in_use: 0,
+            })
+        };
+
+        self.slabs += 1;
+        self.link(slab_ptr);
+
+        Some(slab_ptr)
+    }
+
+    fn link(&mut self, slab_ptr: NonNull<Slab>) {
+        let slab = unsafe { &mut *slab_ptr.as_ptr() };
+        slab.prev = None;
+        slab.next = self.partial;
+
+        if let Some(next) = self.partial {
+            unsafe { (*next.as_ptr()).prev = Some(slab_ptr) };
+        }
+
+        self.partial = Some(slab_ptr);
+    }
+
+    fn unlink(&mut self, slab_ptr: NonNull<Slab>) {
+        let slab = unsafe { &mut *slab_ptr.as_ptr() };
+
+        match slab.prev {
+            Some(prev) => unsafe { (*prev.as_ptr()).next = slab.next },
+            None => self.partial = slab.next,
+        }
+
+        if let Some(next) = slab.next {
+            unsafe { (*next.as_ptr()).prev = slab.prev };
+        }
+
+        slab.prev = None;
+        slab.next = None;
+    }
+}
+
+/// A named [`Cache`] of `T`, for the objects the kernel allocates the most
+pub struct ObjectCache<T> {
+    cache: Cache,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> ObjectCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            cache: Cache::new(name, size_of::<T>(), align_of::<T>()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Move `value` into a new object of the cache
+    pub fn alloc(&self, value: T) -> Option<NonNull<T>> {
+        let object = self.cache.alloc()?.cast::<T>();
+        unsafe { object.as_ptr().write(value) };
+        Some(object)
+    }
+
+    /// Drop `object` and give it back to the cache
+    ///
+    /// SAFETY: `object` must come from [`ObjectCache::alloc`] on this cache,
+    /// and not be used anymore
+    pub unsafe fn free(&self, object: NonNull<T>) {
+        object.as_ptr().drop_in_place();
+        self.cache.free(object.cast());
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
@@ -1,2 +1,32 @@
-pub const PAGE_SHIFT: usize = 12;
-pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+pub use dante_core::{PAGE_SHIFT, PAGE_SIZE};
+
+/// Maximum number of harts supported by the kernel
+pub const MAX_HARTS: usize = 64;
+
+/// `sstatus.SIE`: supervisor interrupts enabled
+const SSTATUS_SIE: usize = 1 << 1;
+
+#[inline]
+pub fn enable_interrupts() {
+    unsafe { core::arch::asm!("csrs sstatus, {0}", in(reg) SSTATUS_SIE) }
+}
+
+#[inline]
+pub fn disable_interrupts() {
+    unsafe { core::arch::asm!("csrc sstatus, {0}", in(reg) SSTATUS_SIE) }
+}
+
+/// Run `f` with supervisor interrupts disabled on the current hart, so that
+/// it can take locks which are also taken by interrupt handlers.
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let sstatus: usize;
+    unsafe { core::arch::asm!("csrrc {0}, sstatus, {1}", out(reg) sstatus, in(reg) SSTATUS_SIE) };
+
+    let result = f();
+
+    if sstatus & SSTATUS_SIE != 0 {
+        enable_interrupts();
+    }
+
+    result
+}
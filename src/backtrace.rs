@@ -0,0 +1,142 @@
+use core::arch::asm;
+use core::ops::Range;
+
+use crate::hart;
+use crate::ksyms;
+use crate::memory::STACK_GUARD_SIZE;
+use crate::prelude::*;
+
+extern "C" {
+    #[link_name = "_estack"]
+    static BOOT_STACK_BOTTOM: u8;
+
+    #[link_name = "_sstack"]
+    static BOOT_STACK_TOP: u8;
+}
+
+/// Frames printed at most, in case the frame pointers form a loop
+const MAX_FRAMES: usize = 64;
+
+/// Iterator over the return addresses of the call stack, following the frame
+/// pointers (`s0`) saved by every function built with `-Cforce-frame-pointers`.
+///
+/// On RISC-V, the frame pointer points just above the frame record, which
+/// holds the return address at `fp - 8` and the caller's frame pointer at `fp - 16`.
+/// The walk stops when the frame pointer leaves the stack, so frames of code
+/// without frame pointers, such as `core`, may end it early.
+pub struct Backtrace {
+    fp: usize,
+    stack: Range<usize>,
+    frames: usize,
+}
+
+impl Backtrace {
+    /// Walk the stack from the frame pointer `fp`
+    pub fn from_fp(fp: usize) -> Self {
+        Self {
+            fp,
+            stack: stack_of(fp).unwrap_or(0..0),
+            frames: 0,
+        }
+    }
+
+    /// Walk the stack of the caller
+    #[inline(always)]
+    pub fn current() -> Self {
+        let fp: usize;
+        unsafe { asm!("mv {0}, s0", out(reg) fp) };
+
+        Self::from_fp(fp)
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let fp = self.fp;
+
+        if self.frames >= MAX_FRAMES
+            || !fp.is_multiple_of(8)
+            || fp < self.stack.start + 16
+            || fp > self.stack.end
+        {
+            return None;
+        }
+
+        let (ra, prev_fp) = unsafe {
+            let record = fp as *const usize;
+            (record.sub(1).read(), record.sub(2).read())
+        };
+
+        if ra == 0 {
+            return None;
+        }
+
+        // The stack grows down, callers have their frame above
+        self.fp = if prev_fp > fp { prev_fp } else { 0 };
+        self.frames += 1;
+
+        Some(ra)
+    }
+}
+
+/// The stack containing `fp`: the boot stack, or the stack or emergency stack
+/// of the current hart
+fn stack_of(fp: usize) -> Option<Range<usize>> {
+    let boot_stack = unsafe {
+        &BOOT_STACK_BOTTOM as *const u8 as usize + STACK_GUARD_SIZE
+            ..&BOOT_STACK_TOP as *const u8 as usize
+    };
+
+    let hart = hart::current();
+    let hart_stack = hart.map(|hart| hart.stack_bottom().as_usize()..hart.stack_top().as_usize());
+    let emergency_stack = hart.map(|hart| hart.emergency_stack().range());
+
+    [Some(boot_stack), hart_stack, emergency_stack]
+        .into_iter()
+        .flatten()
+        .find(|stack| stack.start < fp && fp <= stack.end)
+}
+
+/// Print a frame, `pc` is in the function and `addr` is the address shown
+fn print_frame(index: usize, addr: usize, pc: usize) {
+    match ksyms::lookup(pc) {
+        Some(location) => debug_println!(
+            "  #{index:<2} {addr:#018x} {}+{:#x}",
+            location.symbol.name,
+            addr - location.symbol.addr
+        ),
+        None => debug_println!("  #{index:<2} {addr:#018x} <unknown>"),
+    }
+}
+
+/// Print the call stack, starting at `pc` if given, then the return
+/// addresses found from the frame pointer `fp`
+pub fn print(pc: Option<usize>, fp: usize) {
+    if !ksyms::is_available() {
+        debug_println!("  (no symbol table, build with `just kernel`)");
+    }
+
+    let mut index = 0;
+
+    if let Some(pc) = pc {
+        print_frame(index, pc, pc);
+        index += 1;
+    }
+
+    for ra in Backtrace::from_fp(fp) {
+        // The return address may be past the end of a function ending with a call
+        print_frame(index, ra, ra - 1);
+        index += 1;
+    }
+}
+
+/// Print the call stack of the caller
+#[inline(always)]
+pub fn print_current() {
+    let fp: usize;
+    unsafe { asm!("mv {0}, s0", out(reg) fp) };
+
+    print(None, fp);
+}
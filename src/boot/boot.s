@@ -3,6 +3,10 @@
 .equ PTE_READ, 1 << 1
 .equ PTE_WRITE, 1 << 2
 .equ PTE_EXECUTE, 1 << 3
+.equ SATP_MODE_SV39, 8
+.equ HART_STACK_TOP, 0
+/* Must match memory::DIRECT_MAP_START + memory::RAM_START */
+.equ DIRECT_MAP_RAM_START, 0xffffffc080000000
 
 .section .init
 .global _start
@@ -46,6 +50,12 @@ _abs_start:
 	_PTE_SET \pt, \lvl, \ppn, \flags
 .endm
 
+/* See _PTE_SET. va is an immediate */
+.macro PTE_SET_IMM, pt, va, lvl, ppn, flags
+	li t1, \va
+	_PTE_SET \pt, \lvl, \ppn, \flags
+.endm
+
 /* Clobbers: \ppn t0 t1
  * va must be present in t1
  * Arguments:
@@ -75,40 +85,34 @@ _abs_start:
 	csrw sie, 0
 	csrw sip, 0
 
-	/* Setup identity mapping */
-
-	PPN t2, __page_init_id_lvl2
-	PTE_SET __page_kernel_level_3, _RAM_START, 3, t2, PTE_VALID
+	/* Boot with Sv39, which every RV64 MMU supports. The kernel switches to
+	 * the paging mode of the hart once it builds its own page table. */
 
 	/* 1GB identity mapping */
 	PPN t2, _RAM_START
-	PTE_SET __page_init_id_lvl2, _RAM_START, 2, t2, PTE_VALID | PTE_EXECUTE | PTE_READ | PTE_WRITE
+	PTE_SET __page_boot_root, _RAM_START, 2, t2, PTE_VALID | PTE_EXECUTE | PTE_READ | PTE_WRITE
 
-	PPN t2, __page_kernel_lvl2
-	PTE_SET_FAR __page_kernel_level_3, _KERNEL_CODE_VIRTUAL, 3, t2, PTE_VALID
+	/* 1GB kernel mapping, remapped with per-section permissions by page_table::init */
+	PPN t2, _RAM_START
+	PTE_SET_FAR __page_boot_root, _KERNEL_CODE_VIRTUAL, 2, t2, PTE_VALID | PTE_EXECUTE | PTE_READ | PTE_WRITE
 
-	/* 1GB kernel mapping */
+	/* 1GB of the direct map, extended to all of the RAM by page_table::init */
 	PPN t2, _RAM_START
-	PTE_SET_FAR __page_kernel_lvl2, _KERNEL_CODE_VIRTUAL, 2, t2, PTE_VALID | PTE_EXECUTE | PTE_READ | PTE_WRITE
+	PTE_SET_IMM __page_boot_root, DIRECT_MAP_RAM_START, 2, t2, PTE_VALID | PTE_READ | PTE_WRITE
 
 	/* 2MB kernel stack mapping */
 	PPN t2, __page_stack_lvl1
-	PTE_SET_FAR __page_kernel_lvl2, _VIRTUAL_STACK, 2, t2, PTE_VALID
+	PTE_SET_FAR __page_boot_root, _VIRTUAL_STACK, 2, t2, PTE_VALID
 
 	PPN t2, _PHYSICAL_STACK
 	PTE_SET_FAR __page_stack_lvl1, _VIRTUAL_STACK, 1, t2, PTE_VALID | PTE_READ | PTE_WRITE
 
-	li t1, 9
+	li t1, SATP_MODE_SV39
 	slli t1, t1, 60
-	PPN t0, __page_kernel_level_3
+	PPN t0, __page_boot_root
 	or t0, t0, t1
 	csrw satp, t0
-
-	/* setup global pointer (see .data in hades.x) */
-	LA_FAR gp, __global_pointer$
-
-	/* setup virtual address stack (see .stack in hades.x) */
-	LA_FAR sp, _sstack
+	sfence.vma
 
 	li x1, 0
 	li x2, 0
@@ -140,12 +144,76 @@ _abs_start:
 	li x30, 0
 	li x31, 0
 
+	/* setup global pointer (see .data in hades.x) */
+	LA_FAR gp, __global_pointer$
+
+	/* setup virtual address stack (see .stack in hades.x) */
+	LA_FAR sp, _sstack
+
 	/* jump to main in virtual addresses */
 	LA_FAR a2, _kmain
 	jalr zero, 0(a2)
 
 	.cfi_endproc
 
+/* Entry point of the secondary harts, started through SBI HSM with the MMU off.
+ * a0: hart id
+ * a1: virtual address of the hart's `Hart` structure (see hart.rs)
+ *
+ * Switch to the boot page table to reach the kernel's virtual addresses, then
+ * to the kernel page table in _secondary_virtual, since the `Hart` and the
+ * hart's stack may be outside of the boot direct map. */
+.global _secondary_start
+.balign 4
+_secondary_start:
+	.cfi_startproc
+	.cfi_undefined ra
+
+	csrw sie, 0
+	csrw sip, 0
+
+	li t1, SATP_MODE_SV39
+	slli t1, t1, 60
+	PPN t0, __page_boot_root
+	or t0, t0, t1
+	csrw satp, t0
+	sfence.vma
+
+	LA_FAR t0, _secondary_virtual
+	jr t0
+
+	.cfi_endproc
+
+/* Runs in the kernel code window, which both page tables map. An unsupported
+ * paging mode leaves satp untouched, which hart::secondary_main reports.
+ * Then jump to _secondary_kmain with the hart's stack and tp pointing to its
+ * `Hart`. */
+.section .text.secondary_start, "ax"
+.balign 4
+_secondary_virtual:
+	.cfi_startproc
+	.cfi_undefined ra
+
+	LA_FAR t0, _secondary_satp
+	ld t0, 0(t0)
+	csrw satp, t0
+	sfence.vma
+
+	LA_FAR gp, __global_pointer$
+
+	mv tp, a1
+	ld sp, HART_STACK_TOP(a1)
+
+	/* end of the frame pointer chain for backtraces */
+	li ra, 0
+	li s0, 0
+
+	LA_FAR t0, _secondary_kmain
+	jr t0
+
+	.cfi_endproc
+
+.section .init
 .macro DEFINE_PAGE, name
 
 .align PAGE_SHIFT
@@ -156,7 +224,13 @@ _abs_start:
 
 .endm
 
-DEFINE_PAGE __page_kernel_level_3
-DEFINE_PAGE __page_init_id_lvl2
-DEFINE_PAGE __page_kernel_lvl2
+DEFINE_PAGE __page_boot_root
 DEFINE_PAGE __page_stack_lvl1
+
+/* Physical address of _secondary_start, which the virtual kernel code cannot
+ * compute PC-relatively. */
+.section .rodata.secondary_start, "a"
+.global _SECONDARY_START
+.balign 8
+_SECONDARY_START:
+	.dword _secondary_start
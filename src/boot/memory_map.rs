@@ -0,0 +1,72 @@
+use crate::boot::Region;
+use crate::memory::{
+    virt_to_phys_addr, PhysAddr, KERNEL_PHYS_START, LOG_BUFFER_LEN, LOG_BUFFER_START,
+    PHYSICAL_STACK_START, STACK_LEN,
+};
+
+pub use dante_core::memory_map::{MemoryMap, MemoryRegion, RegionKind};
+
+extern "C" {
+    #[link_name = "_ebss"]
+    static KERNEL_END: u8;
+}
+
+/// The kernel image, from the boot code to the end of `.bss`
+pub fn kernel_image() -> Region {
+    let kernel_end = virt_to_phys_addr(unsafe { &KERNEL_END as *const _ as usize }).unwrap();
+
+    Region::new(
+        PhysAddr::new(KERNEL_PHYS_START),
+        kernel_end - KERNEL_PHYS_START,
+    )
+}
+
+/// Regions used by the kernel from the start, which are reserved in the memory map
+pub fn kernel_regions() -> [MemoryRegion; 3] {
+    [
+        MemoryRegion::new(kernel_image(), RegionKind::Kernel),
+        MemoryRegion::new(
+            Region::new(PhysAddr::new(PHYSICAL_STACK_START), STACK_LEN),
+            RegionKind::Stack,
+        ),
+        MemoryRegion::new(
+            Region::new(PhysAddr::new(LOG_BUFFER_START), LOG_BUFFER_LEN),
+            RegionKind::Log,
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::boot_info;
+
+    #[test_case]
+    fn regions_are_sorted_and_disjoint() {
+        let map = &boot_info().memory_map;
+
+        for (a, b) in map.iter().zip(map.iter().skip(1)) {
+            assert!(a.region.start <= b.region.start);
+            assert!(!a.region.overlaps(&b.region), "{a:?} overlaps {b:?}");
+        }
+    }
+
+    #[test_case]
+    fn kernel_and_dtb_are_reserved() {
+        let boot_info = boot_info();
+        let kind_at = |addr| {
+            boot_info
+                .memory_map
+                .iter()
+                .find(|r| r.region.contains(addr))
+                .map(|r| r.kind)
+        };
+
+        assert_eq!(
+            kind_at(PhysAddr::new(KERNEL_PHYS_START)),
+            Some(RegionKind::Kernel)
+        );
+        assert_eq!(kind_at(boot_info.dtb_addr), Some(RegionKind::Dtb));
+        assert!(boot_info.memory_map.usable_size() > 0);
+    }
+}
@@ -1,6 +1,8 @@
 use core::arch::asm;
 use core::fmt::{self, Write};
 
+use super::dmesg::DmesgWriter;
+use super::Console;
 use crate::memory::virt_to_phys;
 use crate::sbi::{sbi_ret, SbiResult};
 
@@ -36,11 +38,13 @@ macro_rules! debug_println {
 }
 
 pub fn _debug_print_args(args: fmt::Arguments) {
-    let _ = write!(DebugConsole, "{args}");
+    let _ = write!(DmesgWriter, "{args}");
+    let _ = write!(Console, "{args}");
 }
 
 pub fn _debug_println_args(args: fmt::Arguments) {
-    let _ = writeln!(DebugConsole, "{args}");
+    let _ = writeln!(DmesgWriter, "{args}");
+    let _ = writeln!(Console, "{args}");
 }
 
 pub struct DebugConsole;
@@ -0,0 +1,152 @@
+use core::fmt;
+use core::ops::Range;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::boot::Region;
+use crate::memory::PhysAddrExt;
+
+/// Marks a buffer written by a previous boot, `b"DANTELOG"`
+const LOG_MAGIC: u64 = u64::from_le_bytes(*b"DANTELOG");
+
+/// Header at the start of the log region, followed by the data
+#[repr(C)]
+struct Header {
+    magic: u64,
+    /// Size of the data following the header
+    capacity: usize,
+    /// Number of bytes ever written, the data holds the last `capacity` of them
+    head: AtomicUsize,
+}
+
+static LOG: AtomicPtr<Header> = AtomicPtr::new(ptr::null_mut());
+
+/// Kernel log ring buffer.
+///
+/// Writers reserve space with an atomic add on `head` and copy their bytes
+/// without taking any lock, so the log can be written from any context.
+/// A reader racing with writers may see the bytes being overwritten.
+#[derive(Copy, Clone)]
+pub struct Dmesg {
+    header: &'static Header,
+    data: *mut u8,
+}
+
+impl Dmesg {
+    pub fn capacity(&self) -> usize {
+        self.header.capacity
+    }
+
+    /// Number of bytes ever written to the log
+    pub fn head(&self) -> usize {
+        self.header.head.load(Ordering::Acquire)
+    }
+
+    /// Position of the oldest byte still in the log
+    pub fn tail(&self) -> usize {
+        self.head().saturating_sub(self.capacity())
+    }
+
+    pub fn write(&self, bytes: &[u8]) {
+        let capacity = self.capacity();
+        let bytes = &bytes[bytes.len().saturating_sub(capacity)..];
+        let start = self.header.head.fetch_add(bytes.len(), Ordering::AcqRel);
+
+        for (i, &byte) in bytes.iter().enumerate() {
+            unsafe { self.data.add((start + i) % capacity).write_volatile(byte) };
+        }
+    }
+
+    /// Copy the log from position `offset` into `buf`, returning the
+    /// positions that were read.
+    ///
+    /// Reading starts at the oldest byte still in the log if `offset` was overwritten.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Range<usize> {
+        let head = self.head();
+        let start = offset.max(head.saturating_sub(self.capacity()));
+        let len = buf.len().min(head.saturating_sub(start));
+
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = unsafe { self.data.add((start + i) % self.capacity()).read_volatile() };
+        }
+
+        start..start + len
+    }
+
+    /// Write the last `len` bytes of the log to `out`
+    pub fn dump(&self, out: &mut impl fmt::Write, len: usize) -> fmt::Result {
+        let mut buf = [0; 256];
+        let mut offset = self.head().saturating_sub(len);
+
+        loop {
+            let read = self.read(offset, &mut buf);
+            if read.is_empty() {
+                return Ok(());
+            }
+
+            for chunk in buf[..read.len()].utf8_chunks() {
+                out.write_str(chunk.valid())?;
+
+                if !chunk.invalid().is_empty() {
+                    out.write_char(char::REPLACEMENT_CHARACTER)?;
+                }
+            }
+
+            offset = read.end;
+        }
+    }
+}
+
+unsafe impl Send for Dmesg {}
+unsafe impl Sync for Dmesg {}
+
+/// Start logging into `region`, keeping what a previous boot left there.
+///
+/// Returns the number of bytes kept from the previous boot.
+pub fn init(region: Region) -> usize {
+    let header = region.start.to_virt().unwrap().as_mut_ptr::<Header>();
+    let capacity = region.size - size_of::<Header>();
+
+    let kept = unsafe {
+        if (*header).magic == LOG_MAGIC && (*header).capacity == capacity {
+            (*header).head.load(Ordering::Relaxed).min(capacity)
+        } else {
+            header.write(Header {
+                magic: LOG_MAGIC,
+                capacity,
+                head: AtomicUsize::new(0),
+            });
+            0
+        }
+    };
+
+    LOG.store(header, Ordering::Release);
+    kept
+}
+
+/// The kernel log, once initialized
+pub fn dmesg() -> Option<Dmesg> {
+    let header = LOG.load(Ordering::Acquire);
+
+    (!header.is_null()).then(|| {
+        let header = unsafe { &*header };
+
+        Dmesg {
+            header,
+            data: (header as *const Header).wrapping_add(1) as *mut u8,
+        }
+    })
+}
+
+/// Append to the kernel log. Does nothing before [`init`].
+pub struct DmesgWriter;
+
+impl fmt::Write for DmesgWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(dmesg) = dmesg() {
+            dmesg.write(s.as_bytes());
+        }
+
+        Ok(())
+    }
+}
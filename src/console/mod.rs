@@ -1 +1,22 @@
+use core::fmt;
+
 pub mod debug;
+pub mod dmesg;
+pub mod uart;
+
+use debug::DebugConsole;
+
+/// The kernel console: the UART once it is initialized, the SBI debug console
+/// before that and after a panic
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if uart::is_active() {
+            uart::write_str(s);
+            Ok(())
+        } else {
+            DebugConsole.write_str(s)
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use fdt::node::FdtNode;
+use fdt::Fdt;
+use spinning_top::Spinlock as SpinLock;
+
+use crate::arch::without_interrupts;
+use crate::boot::BootInfo;
+use crate::memory::ioremap::ioremap;
+use crate::memory::PhysAddr;
+use crate::plic;
+use crate::register_block;
+use crate::registers::Layout;
+
+const TX_BUFFER_SIZE: usize = 4096;
+const RX_BUFFER_SIZE: usize = 1024;
+
+/// Depth of the 16550A transmit FIFO
+const FIFO_DEPTH: usize = 16;
+
+/// Priority of the UART interrupt at the PLIC
+const IRQ_PRIORITY: u32 = 1;
+
+register_block! {
+    /// Registers of a 16550A, spread out by `reg-shift` and accessed
+    /// `reg-io-width` bytes at a time
+    struct Registers {
+        /// Receive buffer register
+        0 => rbr: u8, ReadOnly;
+        /// Transmit holding register
+        0 => thr: u8, WriteOnly;
+        /// Interrupt enable register
+        1 => ier: u8, ReadWrite {
+            /// Received data available
+            RDI: 0,
+            /// Transmit holding register empty
+            THRI: 1,
+        };
+        /// Interrupt identification register
+        2 => iir: u8, ReadOnly;
+        /// FIFO control register
+        2 => fcr: u8, WriteOnly {
+            ENABLE: 0,
+            CLEAR_RX: 1,
+            CLEAR_TX: 2,
+        };
+        /// Line control register
+        3 => lcr: u8, ReadWrite {
+            /// Data bits minus 5
+            WORD_LENGTH: 0..2,
+            /// 2 stop bits instead of 1
+            STOP_BITS: 2,
+            PARITY: 3,
+            /// Divisor latch access
+            DLAB: 7,
+        };
+        /// Modem control register
+        4 => mcr: u8, ReadWrite {
+            DTR: 0,
+            RTS: 1,
+            /// Gates the interrupt line on most boards
+            OUT2: 3,
+        };
+        /// Line status register
+        5 => lsr: u8, ReadOnly {
+            /// Data ready
+            DR: 0,
+            /// Transmit holding register empty
+            THRE: 5,
+        };
+    }
+}
+
+static UART: SpinLock<Option<Uart>> = SpinLock::new(None);
+
+/// Whether the console output goes to the UART instead of the SBI debug console
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+struct RingBuffer<const N: usize> {
+    data: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        Self {
+            data: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+
+        self.data[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let byte = self.data[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// NS16550A compatible UART, with buffered transmission and reception.
+///
+/// Without an interrupt, the transmit buffer is drained by polling.
+pub struct Uart {
+    registers: Registers,
+    irq: Option<u32>,
+    tx: RingBuffer<TX_BUFFER_SIZE>,
+    rx: RingBuffer<RX_BUFFER_SIZE>,
+}
+
+impl Uart {
+    fn from_fdt(node: &FdtNode<'_, '_>) -> Option<Self> {
+        let compatible = node.compatible()?;
+        if !compatible.all().any(|c| c == "ns16550a" || c == "ns16550") {
+            return None;
+        }
+
+        let reg = node.reg()?.next()?;
+        let size = reg.size.unwrap_or(8);
+        let io = ioremap(PhysAddr::new(reg.starting_address as usize), size).ok()?;
+
+        let cell = |name| node.property(name).and_then(|p| p.as_usize());
+
+        // Registers are `1 << reg-shift` bytes apart, and accessed 1 or 4 bytes at a time
+        let layout = Layout {
+            shift: cell("reg-shift").unwrap_or(0) as u32,
+            io_width: (cell("reg-io-width") == Some(4)).then_some(4),
+        };
+
+        Some(Self {
+            registers: Registers::with_layout(io, layout),
+            irq: plic::irq_of(node),
+            tx: RingBuffer::new(),
+            rx: RingBuffer::new(),
+        })
+    }
+
+    /// Keep the baud rate set by the firmware, but reset the rest of the configuration
+    fn configure(&self) {
+        let regs = &self.registers;
+
+        regs.ier().write(0);
+        // 8 data bits, 1 stop bit, no parity
+        regs.lcr().write(lcr::WORD_LENGTH.val(3));
+        regs.fcr()
+            .write(fcr::ENABLE.mask() | fcr::CLEAR_RX.mask() | fcr::CLEAR_TX.mask());
+        regs.mcr()
+            .write(mcr::DTR.mask() | mcr::RTS.mask() | mcr::OUT2.mask());
+
+        if self.irq.is_some() {
+            regs.ier().write(ier::RDI.mask());
+        }
+    }
+
+    fn queue(&mut self, byte: u8) {
+        while !self.tx.push(byte) {
+            self.drain_polled(1);
+        }
+    }
+
+    fn write_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.queue(b'\r');
+            }
+
+            self.queue(byte);
+        }
+
+        if self.irq.is_some() {
+            self.transmit();
+        } else {
+            self.flush();
+        }
+    }
+
+    /// Fill the transmit FIFO if it is empty, and keep the THRE interrupt
+    /// enabled as long as the buffer is not empty
+    fn transmit(&mut self) {
+        if self.registers.lsr().is_set(lsr::THRE) {
+            for _ in 0..FIFO_DEPTH {
+                let Some(byte) = self.tx.pop() else {
+                    break;
+                };
+
+                self.registers.thr().write(byte);
+            }
+        }
+
+        let ier = if self.tx.is_empty() {
+            ier::RDI.mask()
+        } else {
+            ier::RDI.mask() | ier::THRI.mask()
+        };
+
+        self.registers.ier().write(ier);
+    }
+
+    /// Send at least `count` bytes of the transmit buffer, waiting for the FIFO to empty
+    fn drain_polled(&mut self, count: usize) {
+        let mut sent = 0;
+
+        while sent < count && !self.tx.is_empty() {
+            while !self.registers.lsr().is_set(lsr::THRE) {
+                core::hint::spin_loop();
+            }
+
+            for _ in 0..FIFO_DEPTH {
+                let Some(byte) = self.tx.pop() else {
+                    break;
+                };
+
+                self.registers.thr().write(byte);
+                sent += 1;
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.drain_polled(usize::MAX);
+    }
+
+    fn receive(&mut self) {
+        while self.registers.lsr().is_set(lsr::DR) {
+            let byte = self.registers.rbr().read();
+
+            // Drop the input when nobody reads it
+            let _ = self.rx.push(byte);
+        }
+    }
+}
+
+/// The UART of the `stdout-path` of `/chosen`, with its options such as the baud rate removed
+fn stdout_node<'b, 'a>(fdt: &'b Fdt<'a>) -> Option<FdtNode<'b, 'a>> {
+    let path = fdt
+        .find_node("/chosen")?
+        .property("stdout-path")?
+        .as_str()?;
+    let path = path.split(':').next()?;
+
+    fdt.find_node(path)
+}
+
+/// Take over the console output from the SBI debug console, if the stdout
+/// device is an NS16550A. Requires paging and the PLIC.
+pub fn init(boot_info: &BootInfo) -> bool {
+    let Some(uart) = stdout_node(&boot_info.fdt).and_then(|node| Uart::from_fdt(&node)) else {
+        return false;
+    };
+
+    uart.configure();
+    let irq = uart.irq;
+
+    without_interrupts(|| *UART.lock() = Some(uart));
+
+    if let Some(irq) = irq {
+        plic::register(irq, IRQ_PRIORITY, handle_interrupt);
+    }
+
+    ACTIVE.store(true, Ordering::Release);
+    true
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Interrupt number of the UART, `None` if it is polled
+pub fn irq() -> Option<u32> {
+    without_interrupts(|| UART.lock().as_ref().and_then(|uart| uart.irq))
+}
+
+pub fn write_str(s: &str) {
+    without_interrupts(|| {
+        if let Some(uart) = UART.lock().as_mut() {
+            uart.write_str(s);
+        }
+    })
+}
+
+/// Wait until all the buffered output is sent
+pub fn flush() {
+    without_interrupts(|| {
+        if let Some(uart) = UART.lock().as_mut() {
+            uart.flush();
+        }
+    })
+}
+
+/// Read the received bytes into `buf`, returning how many were read
+pub fn read(buf: &mut [u8]) -> usize {
+    without_interrupts(|| {
+        let mut guard = UART.lock();
+        let Some(uart) = guard.as_mut() else {
+            return 0;
+        };
+
+        // Without an interrupt, nothing fills the buffer in the background
+        if uart.irq.is_none() {
+            uart.receive();
+        }
+
+        let mut len = 0;
+        while len < buf.len() {
+            let Some(byte) = uart.rx.pop() else {
+                break;
+            };
+
+            buf[len] = byte;
+            len += 1;
+        }
+
+        len
+    })
+}
+
+pub fn read_byte() -> Option<u8> {
+    let mut byte = 0;
+    (read(core::slice::from_mut(&mut byte)) == 1).then_some(byte)
+}
+
+/// Stop using the UART for the console, sending what is left of the output
+/// if the UART is not locked. Used on panic, when the lock may never be released.
+pub fn deactivate() {
+    ACTIVE.store(false, Ordering::Release);
+
+    if let Some(mut guard) = UART.try_lock() {
+        if let Some(uart) = guard.as_mut() {
+            uart.flush();
+        }
+    }
+}
+
+fn handle_interrupt(_irq: u32) {
+    if let Some(uart) = UART.lock().as_mut() {
+        // Reading IIR acknowledges the THRE interrupt
+        let _ = uart.registers.iir().read();
+
+        uart.receive();
+        uart.transmit();
+    }
+}
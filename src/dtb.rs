@@ -1,19 +1,19 @@
 use fdt::Fdt;
 
-use crate::{debug_print, debug_println};
+use log::debug;
 
 pub fn debug_dtb(fdt: &Fdt<'_>) {
     // dbg!(&fdt);
 
-    debug_println!("\nDeviceTree:");
-    debug_println!("  Model:               {}", fdt.root().model());
-    debug_println!("  Compatible with:     {}", fdt.root().compatible().first());
-    debug_println!("  CPUs:                {}", fdt.cpus().count());
-    debug_println!("  Memory regions:      {}", fdt.memory().regions().count());
+    debug!("DeviceTree:");
+    debug!("  Model:               {}", fdt.root().model());
+    debug!("  Compatible with:     {}", fdt.root().compatible().first());
+    debug!("  CPUs:                {}", fdt.cpus().count());
+    debug!("  Memory regions:      {}", fdt.memory().regions().count());
 
     let memory = fdt.memory().regions().next().unwrap();
 
-    debug_println!(
+    debug!(
         "  Memory:              {:#x} - {:#X} ({} bytes)",
         memory.starting_address as usize,
         memory.starting_address as usize + memory.size.unwrap(),
@@ -21,14 +21,14 @@ pub fn debug_dtb(fdt: &Fdt<'_>) {
     );
 
     if let Some(reserved_memory) = fdt.find_node("/reserved-memory") {
-        debug_println!(
+        debug!(
             "  Reserved regions:    {}",
             reserved_memory.children().count()
         );
 
         for (i, region) in reserved_memory.children().enumerate() {
             let reg = region.reg().unwrap().next().unwrap();
-            debug_println!(
+            debug!(
                 "  Reserved memory #{i}:  {:#x} - {:#x} ({} bytes)",
                 reg.starting_address as usize,
                 reg.starting_address as usize + reg.size.unwrap(),
@@ -39,31 +39,78 @@ pub fn debug_dtb(fdt: &Fdt<'_>) {
 
     let chosen = fdt.chosen();
     if let Some(bootargs) = chosen.bootargs() {
-        debug_println!("  Boot arguments:      {:?}", bootargs);
+        debug!("  Boot arguments:      {:?}", bootargs);
     }
 
     if let Some(stdout) = chosen.stdout() {
-        debug_println!("  Stdout device:       {}", stdout.name);
+        debug!("  Stdout device:       {}", stdout.name);
     }
 
     let soc = fdt.find_node("/soc");
 
-    debug_println!(
+    debug!(
         "  Has SoC?             {}",
         if soc.is_some() { "yes" } else { "no" }
     );
 
     if let Some(soc) = soc {
-        debug_print!("  SoC Children:");
-
-        for (i, child) in soc.children().enumerate() {
-            if i == 0 {
-                debug_println!("        {}", child.name);
-            } else {
-                debug_println!("                       {}", child.name);
-            }
+        debug!("  SoC children:");
+
+        for child in soc.children() {
+            debug!("    {}", child.name);
         }
     }
+}
+
+/// Whether every CPU of the device tree has the ISA extension `name`, given in
+/// lowercase, in either `riscv,isa-extensions` or `riscv,isa`
+pub fn all_harts_have(fdt: &Fdt<'_>, name: &str) -> bool {
+    fdt.cpus().all(|cpu| {
+        let isa_extensions = cpu
+            .property("riscv,isa-extensions")
+            .map(|p| p.value.split(|&b| b == 0).any(|ext| ext == name.as_bytes()))
+            .unwrap_or(false);
+
+        let isa = cpu
+            .property("riscv,isa")
+            .and_then(|p| p.as_str())
+            .is_some_and(|isa| isa.split('_').skip(1).any(|ext| ext == name));
+
+        isa_extensions || isa
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::boot::boot_info;
+    use crate::page_table::PagingMode;
+
+    #[test_case]
+    fn cpus() {
+        let fdt = &boot_info().fdt;
 
-    debug_println!("");
+        assert!(fdt.cpus().count() > 0);
+        assert!(fdt.cpus().all(|cpu| cpu.timebase_frequency() > 0));
+        assert!(PagingMode::from_fdt(fdt).is_some());
+    }
+
+    #[test_case]
+    fn boot_hart_is_listed() {
+        let boot_info = boot_info();
+
+        assert!(boot_info
+            .fdt
+            .cpus()
+            .any(|cpu| cpu.ids().first() == boot_info.hart_id));
+    }
+
+    #[test_case]
+    fn devices() {
+        let fdt = &boot_info().fdt;
+
+        assert!(fdt.chosen().stdout().is_some());
+        assert!(fdt
+            .find_compatible(&["riscv,plic0", "sifive,plic-1.0.0"])
+            .is_some());
+    }
 }
@@ -0,0 +1,309 @@
+use alloc::format;
+use core::arch::asm;
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use log::info;
+use spinning_top::Spinlock as SpinLock;
+
+use crate::arch::{enable_interrupts, without_interrupts, MAX_HARTS};
+use crate::boot::{try_boot_info, BootInfo};
+use crate::memory::stack::{self, KernelStack};
+use crate::memory::{VirtAddr, STACK_GUARD_SIZE};
+use crate::page_table::KERNEL_PAGE_TABLE;
+use crate::prelude::*;
+use crate::sbi::{sbi_hart_get_status, sbi_hart_start, SbiError};
+use crate::time::{self, Instant};
+use crate::trap;
+
+extern "C" {
+    /// Physical address of `_secondary_start` in boot.s
+    #[link_name = "_SECONDARY_START"]
+    static SECONDARY_START: usize;
+
+    #[link_name = "_estack"]
+    static BOOT_STACK_BOTTOM: u8;
+
+    #[link_name = "_sstack"]
+    static BOOT_STACK_TOP: u8;
+}
+
+/// Stack of the secondary harts
+const HART_STACK_SIZE: usize = 64 * 1024;
+
+/// Stack the trap handler switches to when the stack of a hart overflowed
+const EMERGENCY_STACK_SIZE: usize = 16 * 1024;
+
+/// Space below the stack pointer of `init` left out of the painted boot stack,
+/// for the frames of `init` and `stack::paint` themselves
+const BOOT_STACK_PAINT_MARGIN: usize = 4096;
+
+/// How long a started hart has to come online
+const START_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// `satp` of the kernel page table, which `_secondary_start` switches to
+#[export_name = "_secondary_satp"]
+static SECONDARY_SATP: AtomicUsize = AtomicUsize::new(0);
+
+static HARTS: SpinLock<[Option<&'static Hart>; MAX_HARTS]> = SpinLock::new([None; MAX_HARTS]);
+
+/// Per-hart data, pointed to by `tp` on its hart.
+///
+/// The offsets of `stack_top` and `stack_bottom` must match `HART_STACK_TOP`
+/// in boot.s and `HART_STACK_BOTTOM` in trap.s.
+#[derive(Debug)]
+#[repr(C)]
+pub struct Hart {
+    /// Initial stack pointer of the hart
+    stack_top: usize,
+    /// Lowest mapped address of the stack, the guard page is right below
+    stack_bottom: usize,
+    id: usize,
+    online: AtomicBool,
+    /// Owner of the stack, `None` for the boot stack
+    stack: Option<KernelStack>,
+    emergency_stack: KernelStack,
+}
+
+impl Hart {
+    fn new(
+        id: usize,
+        stack_bottom: VirtAddr,
+        stack_top: VirtAddr,
+        stack: Option<KernelStack>,
+    ) -> Option<Self> {
+        Some(Self {
+            stack_top: stack_top.as_usize(),
+            stack_bottom: stack_bottom.as_usize(),
+            id,
+            online: AtomicBool::new(false),
+            stack,
+            emergency_stack: KernelStack::new(EMERGENCY_STACK_SIZE)?,
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn stack_top(&self) -> VirtAddr {
+        VirtAddr::new(self.stack_top)
+    }
+
+    pub fn stack_bottom(&self) -> VirtAddr {
+        VirtAddr::new(self.stack_bottom)
+    }
+
+    /// Unmapped page below the stack, a fault in it is a stack overflow
+    pub fn guard_page(&self) -> Range<usize> {
+        self.stack_bottom - STACK_GUARD_SIZE..self.stack_bottom
+    }
+
+    pub fn emergency_stack(&self) -> &KernelStack {
+        &self.emergency_stack
+    }
+
+    /// Most bytes of the stack ever used
+    pub fn stack_usage(&self) -> usize {
+        stack::high_water_mark(self.stack_bottom..self.stack_top)
+    }
+
+    pub fn stack_size(&self) -> usize {
+        self.stack_top - self.stack_bottom
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HartError {
+    /// The hart id is not below `MAX_HARTS`
+    InvalidId,
+    /// The hart was already started by the kernel
+    AlreadyStarted,
+    /// No memory left for the stack of the hart
+    OutOfMemory,
+    /// The firmware refused to start the hart
+    Sbi(SbiError),
+    /// The hart did not reach `secondary_main` in time
+    Timeout,
+}
+
+impl fmt::Display for HartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HartError::InvalidId => write!(f, "hart id is not below {MAX_HARTS}"),
+            HartError::AlreadyStarted => write!(f, "hart was already started"),
+            HartError::OutOfMemory => write!(f, "out of memory for the hart stack"),
+            HartError::Sbi(err) => write!(f, "hart_start failed: {err}"),
+            HartError::Timeout => write!(f, "hart did not come online"),
+        }
+    }
+}
+
+/// The current hart, `None` on the boot hart before `init`
+pub fn current() -> Option<&'static Hart> {
+    let tp: usize;
+    unsafe { asm!("mv {0}, tp", out(reg) tp) };
+
+    (tp != 0).then(|| unsafe { &*(tp as *const Hart) })
+}
+
+/// Id of the current hart
+pub fn id() -> usize {
+    try_id().expect("boot info is not initialized")
+}
+
+/// Id of the current hart, `None` before the boot info is initialized
+pub fn try_id() -> Option<usize> {
+    current()
+        .map(Hart::id)
+        .or_else(|| try_boot_info().map(|boot_info| boot_info.hart_id))
+}
+
+/// Hart `id`, if it was started by the kernel
+pub fn get(id: usize) -> Option<&'static Hart> {
+    without_interrupts(|| HARTS.lock().get(id).copied().flatten())
+}
+
+/// Harts started by the kernel, including the boot hart
+pub fn online() -> impl Iterator<Item = &'static Hart> {
+    (0..MAX_HARTS)
+        .filter_map(get)
+        .filter(|hart| hart.is_online())
+}
+
+/// Set up the per-hart data and the emergency stack of the boot hart.
+/// Requires the heap and the kernel page table.
+pub fn init(boot_info: &BootInfo) {
+    let stack_bottom = VirtAddr::new(unsafe { &BOOT_STACK_BOTTOM as *const _ as usize });
+    let stack_bottom = stack_bottom + STACK_GUARD_SIZE;
+    let stack_top = VirtAddr::new(unsafe { &BOOT_STACK_TOP as *const _ as usize });
+    let hart = Hart::new(boot_info.hart_id, stack_bottom, stack_top, None)
+        .expect("out of memory for the emergency stack");
+    let hart: &'static Hart = Box::leak(Box::new(hart));
+
+    // Paint the free part of the boot stack for `Hart::stack_usage`
+    let sp: usize;
+    unsafe {
+        asm!("mv {0}, sp", out(reg) sp);
+        stack::paint(stack_bottom.as_usize()..sp - BOOT_STACK_PAINT_MARGIN);
+    }
+
+    hart.online.store(true, Ordering::Release);
+    without_interrupts(|| HARTS.lock()[hart.id] = Some(hart));
+
+    unsafe { asm!("mv tp, {0}", in(reg) hart) };
+    trap::set_emergency_stack(hart.emergency_stack.top());
+}
+
+/// Log how much of its stack each online hart used
+pub fn log_stack_usage() {
+    info!("Stack usage:");
+    for hart in online() {
+        info!(
+            "  HART {}: {} of {} bytes",
+            hart.id,
+            hart.stack_usage(),
+            hart.stack_size()
+        );
+    }
+}
+
+/// Start every available CPU of the device tree besides the boot hart, and
+/// log the HSM state of each hart
+pub fn start_secondary_harts(boot_info: &BootInfo) {
+    for cpu in boot_info.fdt.cpus() {
+        let id = cpu.ids().first();
+        let before = sbi_hart_get_status(id);
+
+        let result = if id == boot_info.hart_id {
+            Ok(())
+        } else if let Some(status) = cpu
+            .property("status")
+            .and_then(|p| p.as_str())
+            .filter(|&status| status != "okay")
+        {
+            info!("  HART {id}: {status}, not started");
+            continue;
+        } else {
+            start(id).map(|_| ())
+        };
+
+        let describe = |state: Result<_, SbiError>| match state {
+            Ok(state) => format!("{state}"),
+            Err(err) => format!("unknown ({err})"),
+        };
+
+        match result {
+            Ok(()) => info!(
+                "  HART {id}: {} -> {}",
+                describe(before),
+                describe(sbi_hart_get_status(id))
+            ),
+            Err(err) => info!("  HART {id}: {err}"),
+        }
+    }
+}
+
+/// Start hart `id` on `secondary_main` and wait for it to come online
+pub fn start(id: usize) -> Result<&'static Hart, HartError> {
+    if id >= MAX_HARTS {
+        return Err(HartError::InvalidId);
+    }
+
+    if get(id).is_some() {
+        return Err(HartError::AlreadyStarted);
+    }
+
+    let stack = KernelStack::new(HART_STACK_SIZE).ok_or(HartError::OutOfMemory)?;
+    let (stack_bottom, stack_top) = (stack.bottom(), stack.top());
+    let hart = Hart::new(id, stack_bottom, stack_top, Some(stack)).ok_or(HartError::OutOfMemory)?;
+    let hart = Box::new(hart);
+
+    let opaque = &*hart as *const Hart as usize;
+    let satp = without_interrupts(|| KERNEL_PAGE_TABLE.lock().satp());
+    SECONDARY_SATP.store(satp, Ordering::Release);
+    sbi_hart_start(id, unsafe { SECONDARY_START }, opaque).map_err(HartError::Sbi)?;
+
+    // The hart runs with a pointer to it from now on
+    let hart: &'static Hart = Box::leak(hart);
+    without_interrupts(|| HARTS.lock()[id] = Some(hart));
+
+    let deadline = Instant::now() + START_TIMEOUT;
+    while !hart.is_online() {
+        if Instant::now() >= deadline {
+            return Err(HartError::Timeout);
+        }
+
+        core::hint::spin_loop();
+    }
+
+    Ok(hart)
+}
+
+/// Rust entry point of the secondary harts, called by `_secondary_start`
+/// with `tp` pointing to `hart`
+pub fn secondary_main(hart: &'static Hart) -> ! {
+    let activated = without_interrupts(|| unsafe { KERNEL_PAGE_TABLE.lock().activate() });
+    assert!(
+        activated,
+        "HART {}: kernel paging mode unsupported",
+        hart.id
+    );
+
+    trap::init();
+    trap::set_emergency_stack(hart.emergency_stack.top());
+    time::init_hart();
+
+    hart.online.store(true, Ordering::Release);
+    enable_interrupts();
+
+    loop {
+        crate::wfi();
+    }
+}
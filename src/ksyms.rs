@@ -0,0 +1,93 @@
+use core::fmt;
+
+/// Space reserved for the symbol table in the kernel image
+const KSYMS_SIZE: usize = 512 * 1024;
+
+/// Symbol table of the kernel functions, one `<hex address> <name>` line per
+/// symbol sorted by address, padded with zeros.
+///
+/// It is filled after linking by `just symbols`, and stays empty otherwise.
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+extern "C" {
+    // The table is read through the linker symbols so that the compiler
+    // cannot assume it only contains zeros
+    #[link_name = "_sksyms"]
+    static KSYMS_START: u8;
+
+    #[link_name = "_eksyms"]
+    static KSYMS_END: u8;
+
+    #[link_name = "_stext"]
+    static TEXT_START: u8;
+
+    #[link_name = "_etext"]
+    static TEXT_END: u8;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub addr: usize,
+    pub name: &'static str,
+}
+
+/// Location of an address in the code, e.g. `dante::hart::init+0x1c`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub symbol: Symbol,
+    pub offset: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.symbol.name, self.offset)
+    }
+}
+
+fn table() -> &'static str {
+    let table = unsafe {
+        let start = &KSYMS_START as *const u8;
+        let len = &KSYMS_END as *const u8 as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+
+    let len = table.iter().position(|&b| b == 0).unwrap_or(table.len());
+
+    match core::str::from_utf8(&table[..len]) {
+        Ok(table) => table,
+        Err(err) => core::str::from_utf8(&table[..err.valid_up_to()]).unwrap(),
+    }
+}
+
+/// Whether `just symbols` embedded the symbol table
+pub fn is_available() -> bool {
+    !table().is_empty()
+}
+
+pub fn symbols() -> impl Iterator<Item = Symbol> {
+    table().lines().filter_map(|line| {
+        let (addr, name) = line.split_once(' ')?;
+        let addr = usize::from_str_radix(addr, 16).ok()?;
+
+        Some(Symbol { addr, name })
+    })
+}
+
+/// The function containing `addr`, if it is in the kernel code
+pub fn lookup(addr: usize) -> Option<Location> {
+    let text = unsafe { &TEXT_START as *const u8 as usize..&TEXT_END as *const u8 as usize };
+
+    if !text.contains(&addr) {
+        return None;
+    }
+
+    symbols()
+        .take_while(|symbol| symbol.addr <= addr)
+        .last()
+        .map(|symbol| Location {
+            symbol,
+            offset: addr - symbol.addr,
+        })
+}
@@ -0,0 +1,150 @@
+use core::str::FromStr;
+
+use log::{LevelFilter, Log, Metadata, Record};
+use spinning_top::Spinlock as SpinLock;
+
+use crate::arch::without_interrupts;
+use crate::boot::BootInfo;
+use crate::time::Instant;
+use crate::{debug_println, hart};
+
+/// Maximum number of `target=level` directives in the filter
+const MAX_DIRECTIVES: usize = 16;
+
+/// Level of the targets without a directive, unless the filter sets one
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Prefix of the module paths of the kernel, left out of the targets
+const CRATE_PREFIX: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+
+static LOGGER: KernelLogger = KernelLogger;
+
+static FILTER: SpinLock<Filter> = SpinLock::new(Filter::new());
+
+#[derive(Copy, Clone, Debug)]
+struct Directive {
+    target: &'static str,
+    level: LevelFilter,
+}
+
+/// Log level of each target, parsed from a filter such as `page_table=trace,plic=off,info`.
+///
+/// A target matches its submodules, and the longest matching target wins.
+#[derive(Copy, Clone, Debug)]
+pub struct Filter {
+    default: LevelFilter,
+    directives: [Option<Directive>; MAX_DIRECTIVES],
+}
+
+impl Filter {
+    const fn new() -> Self {
+        Self {
+            default: DEFAULT_LEVEL,
+            directives: [None; MAX_DIRECTIVES],
+        }
+    }
+
+    /// Parse a filter, returning the first invalid directive on error
+    pub fn parse(spec: &'static str) -> Result<Self, &'static str> {
+        let mut filter = Self::new();
+        let mut len = 0;
+
+        for directive in spec.split(',').filter(|d| !d.is_empty()) {
+            let Some((target, level)) = directive.split_once('=') else {
+                filter.default = LevelFilter::from_str(directive).map_err(|_| directive)?;
+                continue;
+            };
+
+            let level = LevelFilter::from_str(level).map_err(|_| directive)?;
+            let slot = filter.directives.get_mut(len).ok_or(directive)?;
+
+            *slot = Some(Directive { target, level });
+            len += 1;
+        }
+
+        Ok(filter)
+    }
+
+    pub fn level(&self, target: &str) -> LevelFilter {
+        let matches = |prefix: &str| {
+            target
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        };
+
+        self.directives
+            .iter()
+            .flatten()
+            .filter(|directive| matches(directive.target))
+            .max_by_key(|directive| directive.target.len())
+            .map_or(self.default, |directive| directive.level)
+    }
+
+    /// Most verbose level enabled for any target
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .flatten()
+            .map(|directive| directive.level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let target = kernel_target(metadata.target());
+        metadata.level() <= without_interrupts(|| FILTER.lock().level(target))
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        debug_println!(
+            "[{} H{} {:<5} {}] {}",
+            Instant::now(),
+            hart::id(),
+            record.level(),
+            kernel_target(record.target()),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+fn kernel_target(target: &str) -> &str {
+    target.strip_prefix(CRATE_PREFIX).unwrap_or(target)
+}
+
+/// The `log=` option of the kernel command line
+fn filter_spec(bootargs: &'static str) -> Option<&'static str> {
+    bootargs
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("log="))
+}
+
+/// Install the kernel logger, filtered by the `log=` boot argument from `/chosen`
+pub fn init(boot_info: &'static BootInfo) {
+    let spec = boot_info.fdt.chosen().bootargs().and_then(filter_spec);
+    let filter = spec.map(Filter::parse).unwrap_or(Ok(Filter::new()));
+
+    set_filter(filter.unwrap_or_else(|_| Filter::new()));
+
+    // Only fails when called twice, in which case the logger is already installed
+    let _ = log::set_logger(&LOGGER);
+
+    if let (Some(spec), Err(directive)) = (spec, filter) {
+        log::warn!(
+            "invalid directive {directive:?} in log filter {spec:?}, using the default filter"
+        );
+    }
+}
+
+pub fn set_filter(filter: Filter) {
+    log::set_max_level(filter.max_level());
+    without_interrupts(|| *FILTER.lock() = filter);
+}
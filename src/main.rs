@@ -2,24 +2,44 @@
 #![no_main]
 #![allow(dead_code)]
 #![allow(clippy::missing_safety_doc)]
+#![cfg_attr(nightly, feature(alloc_error_handler))]
+#![cfg_attr(test, feature(custom_test_frameworks))]
+#![cfg_attr(test, test_runner(crate::testing::test_runner))]
+#![cfg_attr(test, reexport_test_harness_main = "test_main")]
 
 extern crate alloc;
 
 mod allocator;
 mod arch;
+mod backtrace;
 mod boot;
 mod console;
 mod dtb;
+mod hart;
+mod ksyms;
+mod logger;
 mod memory;
 mod page_table;
+mod plic;
 mod prelude;
+mod registers;
 mod sbi;
+#[cfg(test)]
+mod testing;
+mod time;
+mod trap;
 
 use core::arch::global_asm;
+use core::fmt::Write;
 use core::panic::PanicInfo;
 
 use boot::BootInfo;
-use memory::PhysAddr;
+use console::debug::DebugConsole;
+use log::info;
+use memory::{PhysAddr, PhysAddrExt};
+
+/// How much of the kernel log is printed on panic
+const PANIC_LOG_DUMP_LEN: usize = 4096;
 
 pub const BANNER: &str = r#"
       ___           ___           ___           ___           ___     
@@ -43,8 +63,38 @@ pub fn wfi() {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    debug_println!("\n==== PANIC ====\n{info}");
-
+    console::uart::deactivate();
+
+    // The log went through the UART, whose buffered output may be lost
+    if let Some(dmesg) = console::dmesg::dmesg() {
+        let _ = writeln!(
+            DebugConsole,
+            "\n==== KERNEL LOG (last {PANIC_LOG_DUMP_LEN} bytes) ===="
+        );
+        let _ = dmesg.dump(&mut DebugConsole, PANIC_LOG_DUMP_LEN);
+    }
+
+    match hart::try_id() {
+        Some(hart_id) => debug_println!("\n==== PANIC on HART {hart_id} ====\n{info}"),
+        None => debug_println!("\n==== PANIC ====\n{info}"),
+    }
+
+    match trap::current_frame() {
+        Some(frame) => {
+            debug_println!("\nWhile handling a trap:\n{frame}");
+            debug_println!("\nBacktrace of the trapped code:");
+            backtrace::print(Some(frame.sepc), frame.regs[8]);
+        }
+        None => {
+            debug_println!("\nBacktrace:");
+            backtrace::print_current();
+        }
+    }
+
+    #[cfg(test)]
+    testing::test_failed();
+
+    #[cfg(not(test))]
     sbi::sbi_panic();
 }
 
@@ -58,33 +108,101 @@ pub unsafe extern "C" fn kmain(hart_id: usize, phys_dtb: usize) -> ! {
     kernel_main(boot_info)
 }
 
-fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    debug_println!("{BANNER}");
-    debug_println!("Kernel arguments:");
-    debug_println!("  HART: {}", boot_info.hart_id);
-    debug_println!("  DeviceTree:");
-    debug_println!("    Physical: {}", boot_info.dtb_addr);
-    debug_println!("    Virtual:  {}", boot_info.dtb_addr.to_virt());
-    debug_println!("");
-
-    page_table::init();
+#[export_name = "_secondary_kmain"]
+pub unsafe extern "C" fn secondary_kmain(_hart_id: usize, hart: &'static hart::Hart) -> ! {
+    hart::secondary_main(hart)
+}
 
-    let memory = boot_info.memory_region();
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    trap::init();
+    let kept_log = console::dmesg::init(boot_info.log_buffer);
+    logger::init(boot_info);
 
-    debug_println!("  Available memory:");
-    debug_println!("    Start: {} ({})", memory.start, memory.start.to_virt());
-    debug_println!("    End:   {} ({})", memory.end(), memory.end().to_virt());
-    debug_println!("    Size:  {} bytes", memory.size);
-    debug_println!("");
+    debug_println!("{BANNER}");
+    info!("Kernel arguments:");
+    info!("  HART: {}", boot_info.hart_id);
+    info!("  DeviceTree:");
+    info!("    Physical: {}", boot_info.dtb_addr);
+    info!("    Virtual:  {}", boot_info.dtb_addr.to_virt().unwrap());
+
+    info!("Memory map:");
+    for region in boot_info.memory_map.iter() {
+        info!("  {:<8} {}", region.kind, region.region);
+    }
+    info!("  Usable:  {} bytes", boot_info.memory_map.usable_size());
+
+    info!(
+        "Kernel log at {}: {kept_log} bytes kept from the previous boot",
+        boot_info.log_buffer
+    );
 
     dtb::debug_dtb(&boot_info.fdt);
 
-    debug_println!("Page table initialized");
-
-    allocator::init_kernel_heap(boot_info);
-    debug_println!("Heap initialized");
-
-    allocator::test_allocations();
-
+    memory::init_frame_allocator(boot_info);
+    info!(
+        "Frame allocator initialized: {} free frames",
+        memory::frame::FRAME_ALLOCATOR.lock().free_frames()
+    );
+
+    page_table::init(boot_info);
+    info!(
+        "Page table initialized ({})",
+        arch::without_interrupts(|| page_table::KERNEL_PAGE_TABLE.lock().mode())
+    );
+
+    memory::add_high_memory(boot_info);
+    info!(
+        "Direct map initialized: {} free frames",
+        memory::frame::FRAME_ALLOCATOR.lock().free_frames()
+    );
+
+    hart::init(boot_info);
+
+    memory::ioremap::init(boot_info);
+    info!(
+        "ioremap initialized: device memory is {}",
+        if memory::ioremap::has_svpbmt() {
+            "mapped as IO (Svpbmt)"
+        } else {
+            "left to the PMAs"
+        }
+    );
+
+    plic::init(boot_info);
+    if let Some(plic) = plic::plic() {
+        info!("PLIC initialized: {} interrupt sources", plic.sources());
+    }
+
+    if console::uart::init(boot_info) {
+        match console::uart::irq() {
+            Some(irq) => info!("UART initialized: IRQ {irq}"),
+            None => info!("UART initialized: polled"),
+        }
+    }
+
+    time::init(boot_info);
+    arch::enable_interrupts();
+    info!(
+        "Timer initialized: {} Hz, using {}",
+        time::timebase_frequency(),
+        if time::has_sstc() {
+            "stimecmp"
+        } else {
+            "SBI TIME"
+        }
+    );
+
+    info!("Harts:");
+    hart::start_secondary_harts(boot_info);
+    info!("{} harts online", hart::online().count());
+
+    #[cfg(test)]
+    test_main();
+
+    time::sleep(core::time::Duration::from_millis(10));
+    hart::log_stack_usage();
+    info!("Uptime: {:?}", time::Instant::now().since_boot());
+
+    console::uart::flush();
     sbi::sbi_shutdown()
 }
@@ -0,0 +1,135 @@
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use dante_core::memory_map::MAX_REGIONS;
+
+use crate::arch::PAGE_SIZE;
+use crate::boot::memory_map::MemoryMap;
+use crate::boot::Region;
+use crate::memory::{align_down, align_up, PhysAddr, VirtAddr, RAM_START};
+use crate::page_table::{PtError, RootPageTable, PTE_READ, PTE_WRITE};
+
+/// Physical address 0 in the direct map, where all of the RAM is mapped at a fixed offset
+pub const DIRECT_MAP_START: usize = 0xffff_ffc0_0000_0000;
+/// Physical memory above this is out of reach of the direct map
+pub const DIRECT_MAP_SIZE: usize = 128 << 30;
+/// Part of the direct map set up by the boot code, used until [`activate`]
+pub const BOOT_DIRECT_MAP: Region = Region::new(PhysAddr::new(RAM_START), 1 << 30);
+
+static mut RUNS: [Region; MAX_REGIONS] = [Region::new(PhysAddr::new(0), 0); MAX_REGIONS];
+/// Number of valid entries of `RUNS`, 0 while the boot direct map is in use
+static RUNS_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// The contiguous runs of physical memory mapped in the direct map: the
+/// accessible memory of `memory_map`, rounded to whole pages.
+fn runs(memory_map: &MemoryMap) -> impl Iterator<Item = Region> + '_ {
+    let mut prev_end = 0;
+
+    memory_map.accessible().filter_map(move |region| {
+        // Neighbouring runs may share a page when the firmware between them is not page aligned
+        let start = align_down(region.start.as_usize(), PAGE_SIZE).max(prev_end);
+        let end = align_up(region.end().as_usize(), PAGE_SIZE).min(DIRECT_MAP_SIZE);
+
+        if start >= end {
+            return None;
+        }
+
+        prev_end = end;
+        Some(Region::new(PhysAddr::new(start), end - start))
+    })
+}
+
+/// Map the accessible memory of `memory_map` in the direct map of `root_pt`.
+///
+/// Everything is mapped RW, except for `read_only` so that the kernel code
+/// never gets a writable alias.
+pub fn map(
+    root_pt: &mut RootPageTable,
+    memory_map: &MemoryMap,
+    read_only: Region,
+) -> Result<(), PtError> {
+    for run in runs(memory_map) {
+        let parts = [
+            (run.start, read_only.start, PTE_READ | PTE_WRITE),
+            (read_only.start, read_only.end(), PTE_READ),
+            (read_only.end(), run.end(), PTE_READ | PTE_WRITE),
+        ];
+
+        for (start, end, flags) in parts {
+            let start = start.max(run.start);
+            let end = end.min(run.end());
+
+            if start < end {
+                let virt = VirtAddr::new(DIRECT_MAP_START + start.as_usize());
+                root_pt.map(virt, start, end - start, flags)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Extend the direct map to the whole of `memory_map`, once the page table
+/// built by [`map`] is active.
+pub fn activate(memory_map: &MemoryMap) {
+    let mut len = 0;
+
+    for run in runs(memory_map) {
+        unsafe { (*addr_of_mut!(RUNS))[len] = run };
+        len += 1;
+    }
+
+    RUNS_LEN.store(len, Ordering::Release);
+}
+
+/// Whether `addr` is mapped in the direct map
+pub fn contains(addr: PhysAddr) -> bool {
+    let len = RUNS_LEN.load(Ordering::Acquire);
+
+    if len == 0 {
+        return BOOT_DIRECT_MAP.contains(addr);
+    }
+
+    // SAFETY: the first `len` runs are never written again once published
+    let runs = unsafe { &(&*addr_of!(RUNS))[..len] };
+    runs.iter().any(|run| run.contains(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::ioremap::IOREMAP_START;
+    use crate::memory::{alloc_frames, free_frames, virt_to_phys, PhysAddrExt, VirtAddrExt};
+
+    #[test_case]
+    fn frames_round_trip() {
+        let frame = alloc_frames(0).unwrap();
+        let virt = frame.to_virt().unwrap();
+
+        assert_eq!(virt.as_usize(), DIRECT_MAP_START + frame.as_usize());
+        assert_eq!(virt.to_phys(), Some(frame));
+
+        free_frames(frame, 0);
+    }
+
+    #[test_case]
+    fn kernel_objects_are_direct_mapped() {
+        static VALUE: u64 = 0x1234_5678;
+
+        let phys = PhysAddr::new(virt_to_phys(&VALUE));
+        let alias = phys.to_virt().unwrap();
+
+        assert_eq!(unsafe { alias.as_ptr::<u64>().read() }, VALUE);
+    }
+
+    #[test_case]
+    fn unmapped_addresses() {
+        // Devices are below the RAM on QEMU virt
+        assert_eq!(PhysAddr::new(0x1000_0000).to_virt(), None);
+        assert_eq!(VirtAddr::new(IOREMAP_START).to_phys(), None);
+        assert_eq!(
+            VirtAddr::new(DIRECT_MAP_START + DIRECT_MAP_SIZE).to_phys(),
+            None
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use core::fmt;
+
+use spinning_top::Spinlock as SpinLock;
+
+use crate::arch::{PAGE_SHIFT, PAGE_SIZE};
+use crate::boot::Region;
+use crate::memory::{align_down, align_up, PhysAddr, PhysAddrExt};
+
+/// Largest block handed out by the buddy allocator: 2^18 frames, ie. 1 GiB.
+pub const MAX_ORDER: usize = 18;
+
+/// Value of a frame in the state map when it is not the head of a free block.
+/// Free block heads store `order + 1`.
+const NOT_FREE: u8 = 0;
+
+pub static FRAME_ALLOCATOR: SpinLock<FrameAllocator> = SpinLock::new(FrameAllocator::empty());
+
+/// Allocate `2^order` physically contiguous frames, aligned on their size.
+pub fn alloc_frames(order: usize) -> Option<PhysAddr> {
+    FRAME_ALLOCATOR.lock().alloc(order)
+}
+
+/// Give back `2^order` frames previously obtained from [`alloc_frames`].
+pub fn free_frames(addr: PhysAddr, order: usize) {
+    FRAME_ALLOCATOR.lock().free(addr, order)
+}
+
+/// Smallest order whose blocks can hold `size` bytes.
+pub fn order_for_size(size: usize) -> usize {
+    let frames = size.div_ceil(PAGE_SIZE).max(1);
+    frames.next_power_of_two().trailing_zeros() as usize
+}
+
+pub const fn order_size(order: usize) -> usize {
+    PAGE_SIZE << order
+}
+
+/// Header written at the start of every free block, linking it into the
+/// free list of its order.
+struct FreeBlock {
+    prev: Option<PhysAddr>,
+    next: Option<PhysAddr>,
+}
+
+impl FreeBlock {
+    /// SAFETY: `addr` must be the head of a free block owned by the allocator
+    unsafe fn at<'a>(addr: PhysAddr) -> &'a mut FreeBlock {
+        &mut *addr.to_virt().unwrap().as_mut_ptr::<FreeBlock>()
+    }
+}
+
+/// Binary buddy allocator over physical frames.
+///
+/// The allocator covers a single span of physical memory, in which only the
+/// frames explicitly added with [`FrameAllocator::add_region`] are ever handed
+/// out. Holes in the span never become free, so they never get merged.
+///
+/// Blocks of order `n` are aligned on `PAGE_SIZE << n` physical bytes, which
+/// lets the page table code back 2 MiB and 1 GiB leaves with a single block.
+pub struct FrameAllocator {
+    /// First frame covered by `state`
+    base: PhysAddr,
+    /// One byte per frame of the span, see [`NOT_FREE`]
+    state: *mut u8,
+    frames: usize,
+    free_lists: [Option<PhysAddr>; MAX_ORDER + 1],
+    total_frames: usize,
+    free_frames: usize,
+}
+
+// SAFETY: the allocator is only ever accessed through `FRAME_ALLOCATOR`'s lock
+unsafe impl Send for FrameAllocator {}
+
+impl FrameAllocator {
+    pub const fn empty() -> Self {
+        Self {
+            base: PhysAddr::new(0),
+            state: core::ptr::null_mut(),
+            frames: 0,
+            free_lists: [None; MAX_ORDER + 1],
+            total_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// Number of bytes of bookkeeping needed to cover `span`
+    pub fn state_size(span: Region) -> usize {
+        span.size >> PAGE_SHIFT
+    }
+
+    /// Create an allocator covering `span`, with no free frames yet.
+    ///
+    /// SAFETY: `state` must point to at least [`FrameAllocator::state_size`]
+    /// bytes which stay valid and untouched for the lifetime of the allocator.
+    pub unsafe fn new(span: Region, state: *mut u8) -> Self {
+        assert!(span.start.as_usize().is_multiple_of(PAGE_SIZE));
+
+        let frames = Self::state_size(span);
+        core::ptr::write_bytes(state, NOT_FREE, frames);
+
+        Self {
+            base: span.start,
+            state,
+            frames,
+            ..Self::empty()
+        }
+    }
+
+    /// Hand over all the whole frames of `region` to the allocator.
+    ///
+    /// SAFETY: the region must be unused RAM inside the span of the allocator
+    pub unsafe fn add_region(&mut self, region: Region) {
+        let mut addr = align_up(region.start.as_usize(), PAGE_SIZE);
+        let end = align_down(region.end().as_usize(), PAGE_SIZE);
+
+        assert!(addr >= self.base.as_usize());
+        assert!(end <= self.base.as_usize() + (self.frames << PAGE_SHIFT));
+
+        while addr < end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| {
+                    addr.is_multiple_of(order_size(order)) && addr + order_size(order) <= end
+                })
+                .unwrap();
+
+            self.total_frames += 1 << order;
+            self.free(PhysAddr::new(addr), order);
+
+            addr += order_size(order);
+        }
+    }
+
+    pub fn alloc(&mut self, order: usize) -> Option<PhysAddr> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let found = (order..=MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let block = self.free_lists[found].unwrap();
+        self.remove(block, found);
+
+        // Split the block, giving back the upper halves until it has the right size
+        for o in (order..found).rev() {
+            self.push(block + order_size(o), o);
+        }
+
+        self.free_frames -= 1 << order;
+
+        Some(block)
+    }
+
+    pub fn free(&mut self, addr: PhysAddr, order: usize) {
+        assert!(order <= MAX_ORDER, "invalid order {order}");
+        assert!(
+            addr.as_usize().is_multiple_of(order_size(order)),
+            "misaligned block {addr} of order {order}"
+        );
+        assert_eq!(
+            self.state(addr),
+            Some(NOT_FREE),
+            "double free or foreign block {addr}"
+        );
+
+        self.free_frames += 1 << order;
+
+        let mut addr = addr;
+        let mut order = order;
+
+        while order < MAX_ORDER {
+            let buddy = PhysAddr::new(addr.as_usize() ^ order_size(order));
+
+            if self.state(buddy) != Some(order as u8 + 1) {
+                break;
+            }
+
+            self.remove(buddy, order);
+            addr = PhysAddr::new(addr.as_usize() & !order_size(order));
+            order += 1;
+        }
+
+        self.push(addr, order);
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of free blocks of each order
+    pub fn free_blocks(&self) -> [usize; MAX_ORDER + 1] {
+        let mut counts = [0; MAX_ORDER + 1];
+
+        for (order, count) in counts.iter_mut().enumerate() {
+            let mut next = self.free_lists[order];
+            while let Some(block) = next {
+                *count += 1;
+                next = unsafe { FreeBlock::at(block).next };
+            }
+        }
+
+        counts
+    }
+
+    fn index(&self, addr: PhysAddr) -> Option<usize> {
+        let offset = addr.as_usize().checked_sub(self.base.as_usize())?;
+        let index = offset >> PAGE_SHIFT;
+        (index < self.frames).then_some(index)
+    }
+
+    fn state(&self, addr: PhysAddr) -> Option<u8> {
+        self.index(addr)
+            .map(|index| unsafe { *self.state.add(index) })
+    }
+
+    fn set_state(&mut self, addr: PhysAddr, state: u8) {
+        let index = self.index(addr).unwrap();
+        unsafe { *self.state.add(index) = state }
+    }
+
+    fn push(&mut self, addr: PhysAddr, order: usize) {
+        let next = self.free_lists[order];
+
+        unsafe {
+            *FreeBlock::at(addr) = FreeBlock { prev: None, next };
+
+            if let Some(next) = next {
+                FreeBlock::at(next).prev = Some(addr);
+            }
+        }
+
+        self.free_lists[order] = Some(addr);
+        self.set_state(addr, order as u8 + 1);
+    }
+
+    fn remove(&mut self, addr: PhysAddr, order: usize) {
+        let block = unsafe { FreeBlock::at(addr) };
+
+        match block.prev {
+            Some(prev) => unsafe { FreeBlock::at(prev).next = block.next },
+            None => self.free_lists[order] = block.next,
+        }
+
+        if let Some(next) = block.next {
+            unsafe { FreeBlock::at(next).prev = block.prev };
+        }
+
+        self.set_state(addr, NOT_FREE);
+    }
+}
+
+impl fmt::Debug for FrameAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameAllocator")
+            .field("base", &self.base)
+            .field("frames", &self.frames)
+            .field("total_frames", &self.total_frames)
+            .field("free_frames", &self.free_frames)
+            .finish()
+    }
+}
@@ -0,0 +1,337 @@
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spinning_top::Spinlock as SpinLock;
+
+use crate::arch::{without_interrupts, PAGE_SIZE};
+use crate::boot::{boot_info, BootInfo, Region};
+use crate::dtb;
+use crate::memory::{align_down, PhysAddr, VirtAddr};
+use crate::page_table::{MemoryType, PtError, KERNEL_PAGE_TABLE, PTE_READ, PTE_WRITE};
+use crate::prelude::*;
+use crate::sbi::sbi_remote_sfence_vma;
+
+/// Virtual range of the [`ioremap`] mappings
+pub const IOREMAP_START: usize = 0xffff_ffff_0000_0000;
+pub const IOREMAP_SIZE: usize = 2 << 30;
+
+/// Whether every hart has Svpbmt, device memory is then mapped with [`MemoryType::Io`]
+static HAS_SVPBMT: AtomicBool = AtomicBool::new(false);
+
+/// Mappings in use, sorted by virtual address
+static AREAS: SpinLock<Vec<IoArea>> = SpinLock::new(Vec::new());
+
+/// A claimed device region and the pages mapping it
+struct IoArea {
+    virt: Range<usize>,
+    region: Region,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IoremapError {
+    /// The region overlaps RAM, which is in the direct map
+    Ram,
+    /// The region, rounded to pages, wraps around the end of the address space
+    Invalid,
+    /// Part of the region is already claimed, as part of the given region
+    Overlapping(Region),
+    /// No virtual range left in the ioremap window
+    OutOfVirtualSpace,
+    Map(PtError),
+}
+
+impl fmt::Display for IoremapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoremapError::Ram => write!(f, "region overlaps RAM"),
+            IoremapError::Invalid => write!(f, "region wraps around the address space"),
+            IoremapError::Overlapping(region) => write!(f, "region overlaps {region}"),
+            IoremapError::OutOfVirtualSpace => write!(f, "ioremap window is full"),
+            IoremapError::Map(err) => write!(f, "mapping failed: {err:?}"),
+        }
+    }
+}
+
+/// Integers which device registers are read and written as, with a single access
+pub trait IoValue: Copy {
+    /// Truncate `bits` to the width of the type
+    fn from_bits(bits: u64) -> Self;
+
+    fn into_bits(self) -> u64;
+}
+
+macro_rules! impl_io_value {
+    ($($ty:ty),*) => {
+        $(
+            impl IoValue for $ty {
+                fn from_bits(bits: u64) -> Self {
+                    bits as $ty
+                }
+
+                fn into_bits(self) -> u64 {
+                    self as u64
+                }
+            }
+        )*
+    };
+}
+
+impl_io_value!(u8, u16, u32, u64);
+
+/// Device registers mapped by [`ioremap`].
+///
+/// Accesses are volatile and checked against the bounds of the region.
+#[derive(Copy, Clone, Debug)]
+pub struct IoMem {
+    base: VirtAddr,
+    region: Region,
+}
+
+impl IoMem {
+    /// Virtual address of the start of the region
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn size(&self) -> usize {
+        self.region.size
+    }
+
+    pub fn read<T: IoValue>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    pub fn write<T: IoValue>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+
+    fn ptr<T: IoValue>(&self, offset: usize) -> *mut T {
+        let size = core::mem::size_of::<T>();
+
+        assert!(
+            offset
+                .checked_add(size)
+                .is_some_and(|end| end <= self.size()),
+            "register at {offset:#x} is outside of {}",
+            self.region
+        );
+        assert!(
+            offset.is_multiple_of(size),
+            "register at {offset:#x} is misaligned"
+        );
+
+        (self.base + offset).as_mut_ptr()
+    }
+}
+
+/// Look for the Svpbmt extension
+pub fn init(boot_info: &BootInfo) {
+    HAS_SVPBMT.store(
+        dtb::all_harts_have(&boot_info.fdt, "svpbmt"),
+        Ordering::Relaxed,
+    );
+}
+
+pub fn has_svpbmt() -> bool {
+    HAS_SVPBMT.load(Ordering::Relaxed)
+}
+
+/// Map the `size` bytes of device registers at `phys` in the ioremap window.
+///
+/// Each byte of device memory can only be claimed once: regions overlapping
+/// an existing mapping are refused.
+pub fn ioremap(phys: PhysAddr, size: usize) -> Result<IoMem, IoremapError> {
+    let size = size.max(1);
+    let end = phys
+        .as_usize()
+        .checked_add(size)
+        .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE))
+        .ok_or(IoremapError::Invalid)?;
+    let region = Region::new(phys, size);
+
+    // Every region of the memory map is RAM, reserved or not
+    let memory_map = &boot_info().memory_map;
+    if memory_map.iter().any(|ram| ram.region.overlaps(&region)) {
+        return Err(IoremapError::Ram);
+    }
+
+    let start = align_down(phys.as_usize(), PAGE_SIZE);
+    let len = end - start;
+    let virt = reserve(region, len)?;
+
+    let memory_type = if has_svpbmt() {
+        MemoryType::Io
+    } else {
+        MemoryType::Pma
+    };
+
+    let mapped = without_interrupts(|| {
+        KERNEL_PAGE_TABLE.lock().map_with_memory_type(
+            VirtAddr::new(virt),
+            PhysAddr::new(start),
+            len,
+            PTE_READ | PTE_WRITE,
+            memory_type,
+        )
+    });
+
+    if let Err(err) = mapped {
+        release(virt);
+        return Err(IoremapError::Map(err));
+    }
+
+    Ok(IoMem {
+        base: VirtAddr::new(virt + phys.as_usize() - start),
+        region,
+    })
+}
+
+/// Unmap registers mapped by [`ioremap`], so that their region can be claimed again.
+///
+/// SAFETY: neither `io` nor any copy of it must be used anymore
+pub unsafe fn iounmap(io: IoMem) {
+    let virt = align_down(io.base.as_usize(), PAGE_SIZE);
+    let len = release(virt).unwrap_or_else(|| panic!("{} is not mapped by ioremap", io.base));
+
+    without_interrupts(|| {
+        KERNEL_PAGE_TABLE
+            .lock()
+            .unmap(VirtAddr::new(virt), len)
+            .unwrap()
+    });
+
+    // `unmap` only flushed the TLB of this hart
+    sbi_remote_sfence_vma(0, usize::MAX, virt, len).unwrap();
+}
+
+/// Claim `region` and reserve `len` bytes of virtual memory for it, with first fit
+fn reserve(region: Region, len: usize) -> Result<usize, IoremapError> {
+    without_interrupts(|| {
+        let mut areas = AREAS.lock();
+
+        if let Some(area) = areas.iter().find(|area| area.region.overlaps(&region)) {
+            return Err(IoremapError::Overlapping(area.region));
+        }
+
+        let mut start = IOREMAP_START;
+        let mut index = 0;
+        for area in areas.iter() {
+            if area.virt.start - start >= len {
+                break;
+            }
+            start = area.virt.end;
+            index += 1;
+        }
+
+        if IOREMAP_START + IOREMAP_SIZE - start < len {
+            return Err(IoremapError::OutOfVirtualSpace);
+        }
+
+        areas.insert(
+            index,
+            IoArea {
+                virt: start..start + len,
+                region,
+            },
+        );
+        Ok(start)
+    })
+}
+
+/// Release the area starting at `virt`, returning its size
+fn release(virt: usize) -> Option<usize> {
+    without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        let index = areas
+            .binary_search_by_key(&virt, |area| area.virt.start)
+            .ok()?;
+        Some(areas.remove(index).virt.len())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{KERNEL_PHYS_START, RAM_START};
+
+    /// There are no devices at 0x70000000 on QEMU virt, the registers are never accessed
+    const TEST_PHYS: usize = 0x7000_0000;
+
+    #[test_case]
+    fn ioremap_iounmap() {
+        let io = ioremap(PhysAddr::new(TEST_PHYS + 0x10), 0x20).unwrap();
+        assert!((IOREMAP_START..IOREMAP_START + IOREMAP_SIZE).contains(&io.base().as_usize()));
+        assert_eq!(io.base().as_usize() % PAGE_SIZE, 0x10);
+
+        let translation = KERNEL_PAGE_TABLE.lock().translate(io.base()).unwrap();
+        assert_eq!(translation.phys, PhysAddr::new(TEST_PHYS + 0x10));
+
+        unsafe { iounmap(io) };
+        assert_eq!(
+            KERNEL_PAGE_TABLE.lock().translate(io.base()).unwrap_err(),
+            PtError::NotMapped
+        );
+    }
+
+    #[test_case]
+    fn overlapping_claims_are_refused() {
+        let io = ioremap(PhysAddr::new(TEST_PHYS), 0x100).unwrap();
+
+        assert_eq!(
+            ioremap(PhysAddr::new(TEST_PHYS + 0xf8), 0x10).unwrap_err(),
+            IoremapError::Overlapping(io.region())
+        );
+
+        // Another device in the same page is fine
+        let next = ioremap(PhysAddr::new(TEST_PHYS + 0x100), 0x100).unwrap();
+
+        unsafe {
+            iounmap(next);
+            iounmap(io);
+        }
+
+        let io = ioremap(PhysAddr::new(TEST_PHYS + 0xf8), 0x10).unwrap();
+        unsafe { iounmap(io) };
+    }
+
+    #[test_case]
+    fn ram_is_refused() {
+        assert_eq!(
+            ioremap(PhysAddr::new(KERNEL_PHYS_START), PAGE_SIZE).unwrap_err(),
+            IoremapError::Ram
+        );
+        assert_eq!(
+            ioremap(PhysAddr::new(RAM_START - PAGE_SIZE), 2 * PAGE_SIZE).unwrap_err(),
+            IoremapError::Ram
+        );
+
+        // Neither the first nor the last byte is in RAM
+        let ram_end = boot_info()
+            .memory_map
+            .iter()
+            .map(|ram| ram.region.end().as_usize())
+            .max()
+            .unwrap();
+        assert_eq!(
+            ioremap(
+                PhysAddr::new(RAM_START - PAGE_SIZE),
+                ram_end + PAGE_SIZE - (RAM_START - PAGE_SIZE)
+            )
+            .unwrap_err(),
+            IoremapError::Ram
+        );
+    }
+
+    #[test_case]
+    fn wrapping_region_is_refused() {
+        assert_eq!(
+            ioremap(PhysAddr::new(usize::MAX - PAGE_SIZE + 1), 2 * PAGE_SIZE).unwrap_err(),
+            IoremapError::Invalid
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use crate::arch::PAGE_SIZE;
+use crate::boot::{BootInfo, Region};
+
+pub mod direct_map;
+pub mod frame;
+pub mod ioremap;
+pub mod stack;
+pub mod vmalloc;
+
+pub use dante_core::addr::{align_down, align_up, PhysAddr, VirtAddr};
+pub use direct_map::{BOOT_DIRECT_MAP, DIRECT_MAP_SIZE, DIRECT_MAP_START};
+pub use frame::{alloc_frames, free_frames, order_size, FrameAllocator};
+
+// TODO: load these from symbols
+pub const RAM_START: usize = 0x80000000;
+pub const KERNEL_PHYS_START: usize = RAM_START + 0x2000000;
+pub const PHYSICAL_STACK_START: usize = KERNEL_PHYS_START + 16 * 1024 * 1024;
+pub const STACK_LEN: usize = 2 * 1024 * 1024;
+/// Bottom of the boot stack left unmapped by `page_table::init`, to catch overflows
+pub const STACK_GUARD_SIZE: usize = PAGE_SIZE;
+/// The kernel log lives at a fixed address after the boot stack, so it is found again after a warm reset
+pub const LOG_BUFFER_START: usize = PHYSICAL_STACK_START + STACK_LEN;
+pub const LOG_BUFFER_LEN: usize = 256 * 1024;
+/// Size of the RAM window mapped at `KERNEL_CODE_VIRTUAL` by the boot code
+pub const RAM_WINDOW_SIZE: usize = 1 << 30;
+/// Virtual range of the kernel heap, which is mapped as it grows
+pub const HEAP_START: usize = 0xffff_ffe0_0000_0000;
+pub const HEAP_MAX_SIZE: usize = 4 << 30;
+
+extern "C" {
+    #[link_name = "_KERNEL_CODE_VIRTUAL"]
+    pub static KERNEL_CODE_VIRTUAL: u8;
+
+    #[link_name = "_VIRTUAL_STACK"]
+    pub static KERNEL_STACK_VIRTUAL: u8;
+
+    #[link_name = "_stext"]
+    pub static KERNEL_TEXT_START: u8;
+
+    #[link_name = "_srodata"]
+    pub static KERNEL_RODATA_START: u8;
+
+    #[link_name = "_sdata"]
+    pub static KERNEL_DATA_START: u8;
+}
+
+/// Address of physical memory in the direct map
+pub trait PhysAddrExt {
+    fn to_virt(self) -> Option<VirtAddr>;
+}
+
+impl PhysAddrExt for PhysAddr {
+    fn to_virt(self) -> Option<VirtAddr> {
+        phys_to_virt_addr(self.as_usize()).map(VirtAddr::new)
+    }
+}
+
+/// Physical address of kernel memory
+pub trait VirtAddrExt {
+    fn to_phys(self) -> Option<PhysAddr>;
+}
+
+impl VirtAddrExt for VirtAddr {
+    fn to_phys(self) -> Option<PhysAddr> {
+        virt_to_phys_addr(self.as_usize()).map(PhysAddr::new)
+    }
+}
+
+/// Physical address of a kernel object
+pub fn virt_to_phys<T: ?Sized>(v: &T) -> usize {
+    let addr = v as *const T as *const () as usize;
+    virt_to_phys_addr(addr).unwrap_or_else(|| panic!("{addr:#x} is not kernel memory"))
+}
+
+/// Physical address of `addr` in the kernel code window, the kernel stack or
+/// the direct map
+pub fn virt_to_phys_addr(addr: usize) -> Option<usize> {
+    let virtual_code_start = unsafe { &KERNEL_CODE_VIRTUAL as *const _ as usize };
+    let virtual_stack_start = unsafe { &KERNEL_STACK_VIRTUAL as *const _ as usize };
+
+    if addr >= virtual_code_start {
+        let offset = addr - virtual_code_start;
+        (offset < RAM_WINDOW_SIZE).then_some(RAM_START + offset)
+    } else if (virtual_stack_start + STACK_GUARD_SIZE..virtual_stack_start + STACK_LEN)
+        .contains(&addr)
+    {
+        let offset = addr - virtual_stack_start;
+        Some(PHYSICAL_STACK_START + offset)
+    } else if (DIRECT_MAP_START..DIRECT_MAP_START + DIRECT_MAP_SIZE).contains(&addr) {
+        let phys = addr - DIRECT_MAP_START;
+        direct_map::contains(PhysAddr::new(phys)).then_some(phys)
+    } else {
+        None
+    }
+}
+
+/// Address of `addr` in the direct map, if it is mapped there
+pub fn phys_to_virt_addr(addr: usize) -> Option<usize> {
+    direct_map::contains(PhysAddr::new(addr)).then_some(DIRECT_MAP_START + addr)
+}
+
+/// The whole pages of `region` inside `window`
+fn clip(region: Region, window: Region) -> Option<Region> {
+    let start = align_up(region.start.max(window.start).as_usize(), PAGE_SIZE);
+    let end = align_down(region.end().min(window.end()).as_usize(), PAGE_SIZE);
+    (start < end).then(|| Region::new(PhysAddr::new(start), end - start))
+}
+
+/// Hand over the usable regions of the memory map inside the boot direct map
+/// to the frame allocator.
+///
+/// The allocator covers all of the usable RAM, and its bookkeeping is carved
+/// out of the first usable region large enough to hold it. The rest of the
+/// RAM is handed over by [`add_high_memory`] once it is mapped.
+pub fn init_frame_allocator(boot_info: &BootInfo) {
+    let direct_map = Region::new(PhysAddr::new(0), DIRECT_MAP_SIZE);
+    let usable = || {
+        boot_info
+            .memory_map
+            .usable()
+            .filter_map(move |region| clip(region, direct_map))
+    };
+    let boot_usable = || usable().filter_map(|region| clip(region, BOOT_DIRECT_MAP));
+
+    let start = usable().map(|r| r.start).min().expect("no usable memory");
+    let end = usable().map(|r| r.end()).max().unwrap();
+    let span = Region::new(start, end - start);
+
+    let state_size = align_up(FrameAllocator::state_size(span), PAGE_SIZE);
+    let state = boot_usable()
+        .find(|r| r.size >= state_size)
+        .expect("not enough memory for the frame allocator")
+        .start;
+
+    let mut allocator = frame::FRAME_ALLOCATOR.lock();
+
+    unsafe {
+        *allocator = FrameAllocator::new(span, state.to_virt().unwrap().as_mut_ptr());
+
+        for region in boot_usable() {
+            if region.start == state {
+                allocator.add_region(Region::new(state + state_size, region.size - state_size));
+            } else {
+                allocator.add_region(region);
+            }
+        }
+    }
+}
+
+/// Hand over the usable RAM outside of the boot direct map to the frame
+/// allocator, once `page_table::init` mapped it.
+pub fn add_high_memory(boot_info: &BootInfo) {
+    let below_boot = Region::new(PhysAddr::new(0), BOOT_DIRECT_MAP.start.as_usize());
+    let above_boot = Region::new(
+        BOOT_DIRECT_MAP.end(),
+        DIRECT_MAP_SIZE - BOOT_DIRECT_MAP.end().as_usize(),
+    );
+
+    let mut allocator = frame::FRAME_ALLOCATOR.lock();
+
+    for region in boot_info.memory_map.usable() {
+        for window in [below_boot, above_boot] {
+            if let Some(region) = clip(region, window) {
+                unsafe { allocator.add_region(region) };
+            }
+        }
+    }
+}
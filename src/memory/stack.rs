@@ -0,0 +1,95 @@
+use core::ops::Range;
+
+use crate::arch::PAGE_SIZE;
+use crate::memory::vmalloc::{vfree, vmalloc};
+use crate::memory::{align_up, VirtAddr};
+
+/// Written over unused stacks, so that [`high_water_mark`] finds how deep they were used
+const STACK_PAINT: u64 = 0x5354_4143_4b50_4149;
+
+/// Kernel stack, with an unmapped guard page below it.
+///
+/// The stack is freed when dropped, it must not be in use anymore by then.
+#[derive(Debug)]
+pub struct KernelStack {
+    bottom: VirtAddr,
+    size: usize,
+}
+
+impl KernelStack {
+    /// Allocate a painted stack of at least `size` bytes
+    pub fn new(size: usize) -> Option<Self> {
+        let size = align_up(size, PAGE_SIZE);
+        let bottom = vmalloc(size)?;
+        let stack = Self { bottom, size };
+
+        unsafe { paint(stack.range()) };
+        Some(stack)
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.bottom + self.size
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.bottom.as_usize()..self.top().as_usize()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        unsafe { vfree(self.bottom) };
+    }
+}
+
+/// Paint the unused part of a stack, from `stack.start` up to `stack.end`
+///
+/// SAFETY: the range must be mapped and unused
+pub unsafe fn paint(stack: Range<usize>) {
+    for word in stack.step_by(8) {
+        (word as *mut u64).write_volatile(STACK_PAINT);
+    }
+}
+
+/// Number of bytes of a painted stack which were ever used
+pub fn high_water_mark(stack: Range<usize>) -> usize {
+    let lowest_used = stack
+        .clone()
+        .step_by(8)
+        .find(|&word| unsafe { (word as *const u64).read_volatile() } != STACK_PAINT)
+        .unwrap_or(stack.end);
+
+    stack.end - lowest_used
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page_table::{PtError, KERNEL_PAGE_TABLE};
+
+    #[test_case]
+    fn guard_page_is_unmapped() {
+        let stack = KernelStack::new(3 * PAGE_SIZE).unwrap();
+        let root_pt = KERNEL_PAGE_TABLE.lock();
+
+        assert!(root_pt.translate(stack.bottom()).is_ok());
+        assert_eq!(
+            root_pt.translate(stack.bottom() - PAGE_SIZE).unwrap_err(),
+            PtError::NotMapped
+        );
+    }
+
+    #[test_case]
+    fn high_water_mark_of_painted_stack() {
+        let stack = KernelStack::new(2 * PAGE_SIZE).unwrap();
+        assert_eq!(high_water_mark(stack.range()), 0);
+
+        let used = stack.top() - 104;
+        unsafe { used.as_mut_ptr::<u64>().write(0) };
+        assert_eq!(high_water_mark(stack.range()), 104);
+    }
+}
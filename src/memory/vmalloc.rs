@@ -0,0 +1,218 @@
+use core::ops::Range;
+
+use spinning_top::Spinlock as SpinLock;
+
+use crate::arch::{without_interrupts, PAGE_SIZE};
+use crate::memory::{alloc_frames, free_frames, VirtAddr};
+use crate::page_table::{KERNEL_PAGE_TABLE, PTE_READ, PTE_WRITE};
+use crate::prelude::*;
+use crate::sbi::sbi_remote_sfence_vma;
+
+/// Virtual range of the [`vmalloc`] allocations
+pub const VMALLOC_START: usize = 0xffff_fff0_0000_0000;
+pub const VMALLOC_SIZE: usize = 16 << 30;
+
+/// Unmapped pages after every allocation, which turn overflows into page faults
+const GUARD_SIZE: usize = PAGE_SIZE;
+
+/// Virtual ranges in use, sorted, each followed by its guard
+static AREAS: SpinLock<Vec<Range<usize>>> = SpinLock::new(Vec::new());
+
+/// Reserve `size` bytes of virtual memory and a guard, with first fit
+fn reserve(size: usize) -> Option<usize> {
+    without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        let needed = size.checked_add(GUARD_SIZE)?;
+
+        let mut start = VMALLOC_START;
+        let mut index = 0;
+        for area in areas.iter() {
+            if area.start - start >= needed {
+                break;
+            }
+            start = area.end + GUARD_SIZE;
+            index += 1;
+        }
+
+        if VMALLOC_START + VMALLOC_SIZE - start < needed {
+            return None;
+        }
+
+        areas.insert(index, start..start + size);
+        Some(start)
+    })
+}
+
+/// Release the area starting at `start`, returning its size
+fn release(start: usize) -> Option<usize> {
+    without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        let index = areas.binary_search_by_key(&start, |area| area.start).ok()?;
+        Some(areas.remove(index).len())
+    })
+}
+
+/// Allocate `size` bytes of virtually contiguous memory, backed by frames
+/// which need not be physically contiguous.
+///
+/// The memory is not initialized. The pages right below and after it are
+/// never mapped.
+pub fn vmalloc(size: usize) -> Option<VirtAddr> {
+    let size = size.max(1).checked_next_multiple_of(PAGE_SIZE)?;
+    let start = reserve(size)?;
+
+    let mapped = without_interrupts(|| {
+        let mut root_pt = KERNEL_PAGE_TABLE.lock();
+        let mut mapped = 0;
+
+        while mapped < size {
+            let Some(frame) = alloc_frames(0) else {
+                break;
+            };
+
+            let virt = VirtAddr::new(start + mapped);
+            if root_pt
+                .map(virt, frame, PAGE_SIZE, PTE_READ | PTE_WRITE)
+                .is_err()
+            {
+                // No frame left for the page tables
+                free_frames(frame, 0);
+                break;
+            }
+            mapped += PAGE_SIZE;
+        }
+
+        mapped
+    });
+
+    if mapped < size {
+        unmap(start, mapped);
+        release(start);
+        return None;
+    }
+
+    Some(VirtAddr::new(start))
+}
+
+/// Unmap and free the memory of an allocation of [`vmalloc`].
+///
+/// SAFETY: `virt` must come from [`vmalloc`], and the memory must not be used anymore
+pub unsafe fn vfree(virt: VirtAddr) {
+    let size =
+        release(virt.as_usize()).unwrap_or_else(|| panic!("{virt} is not a vmalloc allocation"));
+
+    unmap(virt.as_usize(), size);
+}
+
+/// Unmap `size` bytes at `start` and free their frames
+fn unmap(start: usize, size: usize) {
+    // Allocated before the page table is locked, see `KERNEL_PAGE_TABLE`
+    let mut frames = Vec::with_capacity(size / PAGE_SIZE);
+
+    without_interrupts(|| {
+        let mut root_pt = KERNEL_PAGE_TABLE.lock();
+
+        for page in (start..start + size).step_by(PAGE_SIZE) {
+            let virt = VirtAddr::new(page);
+            frames.push(root_pt.translate(virt).unwrap().phys);
+            root_pt.unmap(virt, PAGE_SIZE).unwrap();
+        }
+    });
+
+    // `unmap` only flushed the TLB of this hart, the frames may still be
+    // accessed through the stale entries of other harts until then
+    sbi_remote_sfence_vma(0, usize::MAX, start, size).unwrap();
+
+    for frame in frames {
+        free_frames(frame, 0);
+    }
+}
+
+/// Number of allocations and bytes allocated by [`vmalloc`]
+pub fn usage() -> (usize, usize) {
+    without_interrupts(|| {
+        let areas = AREAS.lock();
+        (areas.len(), areas.iter().map(|area| area.len()).sum())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::PhysAddrExt;
+    use crate::page_table::PtError;
+
+    #[test_case]
+    fn vmalloc_vfree() {
+        let (count, bytes) = usage();
+        let size = 5 * PAGE_SIZE + 1;
+        let virt = vmalloc(size).unwrap();
+
+        assert_eq!(usage(), (count + 1, bytes + 6 * PAGE_SIZE));
+
+        let words = 6 * PAGE_SIZE / 8;
+        let slice = unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u64>(), words) };
+        for (i, word) in slice.iter_mut().enumerate() {
+            *word = i as u64;
+        }
+        assert_eq!(slice[words - 1], words as u64 - 1);
+
+        // Every page is backed by its own frame, also reachable through the direct map
+        let frame = KERNEL_PAGE_TABLE
+            .lock()
+            .translate(virt + 3 * PAGE_SIZE)
+            .unwrap()
+            .phys;
+        let alias = frame.to_virt().unwrap();
+        assert_eq!(
+            unsafe { alias.as_ptr::<u64>().read() },
+            3 * PAGE_SIZE as u64 / 8
+        );
+
+        unsafe { vfree(virt) };
+        assert_eq!(usage(), (count, bytes));
+        assert_eq!(
+            KERNEL_PAGE_TABLE.lock().translate(virt).unwrap_err(),
+            PtError::NotMapped
+        );
+    }
+
+    #[test_case]
+    fn allocations_are_separated_by_guards() {
+        let a = vmalloc(PAGE_SIZE).unwrap();
+        let b = vmalloc(PAGE_SIZE).unwrap();
+        let guard = a + PAGE_SIZE;
+
+        assert!(b.as_usize() >= guard.as_usize() + GUARD_SIZE || b < a);
+        assert_eq!(
+            KERNEL_PAGE_TABLE.lock().translate(guard).unwrap_err(),
+            PtError::NotMapped
+        );
+
+        unsafe {
+            vfree(a);
+            vfree(b);
+        }
+    }
+
+    #[test_case]
+    fn freed_ranges_are_reused() {
+        let a = vmalloc(PAGE_SIZE).unwrap();
+        unsafe { vfree(a) };
+
+        let b = vmalloc(PAGE_SIZE).unwrap();
+        assert_eq!(a, b);
+        unsafe { vfree(b) };
+    }
+
+    #[test_case]
+    fn oversized_allocations_fail() {
+        let (count, bytes) = usage();
+
+        // Overflows when rounded up to pages, and when the guard is added
+        assert!(vmalloc(usize::MAX).is_none());
+        assert!(vmalloc(usize::MAX - PAGE_SIZE + 1).is_none());
+        assert!(vmalloc(VMALLOC_SIZE).is_none());
+        assert_eq!(usage(), (count, bytes));
+    }
+}
@@ -2,133 +2,257 @@ use alloc::fmt;
 use core::arch::asm;
 use core::ops;
 use core::ops::Index;
+use core::ptr::NonNull;
 
+use log::{debug, error};
 use spinning_top::Spinlock as SpinLock;
 
-use crate::arch::PAGE_SHIFT;
+use crate::allocator::ObjectCache;
+use crate::arch::{without_interrupts, PAGE_SHIFT, PAGE_SIZE};
+use crate::boot::memory_map::{kernel_image, MemoryMap};
+use crate::boot::{BootInfo, Region};
 use crate::memory::{
-    virt_to_phys, KERNEL_CODE_VIRTUAL, KERNEL_STACK_VIRTUAL, PHYSICAL_STACK_START, RAM_START,
-    RAM_VIRTUAL_START,
+    direct_map, virt_to_phys, PhysAddr, PhysAddrExt, VirtAddr, VirtAddrExt, KERNEL_CODE_VIRTUAL,
+    KERNEL_DATA_START, KERNEL_RODATA_START, KERNEL_STACK_VIRTUAL, KERNEL_TEXT_START,
+    PHYSICAL_STACK_START, RAM_WINDOW_SIZE, STACK_GUARD_SIZE, STACK_LEN,
 };
 
-// Page tables use the RSW bits of the first entry to denote if the page was allocated by the
-// buddy allocator, or if it is a statically allocated (and it should not be unmapped!)
+pub use dante_core::page_table::{
+    check_entry, vpn, Inconsistency, Mapping, MemoryType, PageSize, PageTableEntry, PagingMode,
+    PtError, PteFlags, Translation, Violation, PTE_EXECUTE, PTE_READ, PTE_VALID, PTE_WRITE,
+};
 
-#[derive(Debug)]
-pub enum PtError {
-    AlreadyMappedLeaf,
-    AlreadMappedIntermediate,
-}
+/// RSW bits of the first entry of the page tables which are statically allocated, and must
+/// never be given back to the buddy allocator
+const RSW_STATIC: u8 = 0b10;
+
+/// Size of the pages mapped by a Svnapot leaf
+const NAPOT_SIZE: usize = 64 * 1024;
 
-macro_rules! declare_flags {
-     (flags {
-         $($flag:ident : $value:expr),* $(,)?
-     }) => {
-         $(
-             #[allow(unused)]
-             pub const $flag: u8 = 1 << $value;
-         )*
-
-         const FLAGS: [(u8, &'static str); 8] = [
-            $(($value, stringify!($flag)),)*
-         ];
-     };
- }
-
-declare_flags! {
-    flags {
-        PTE_VALID: 0,
-        PTE_READ: 1,
-        PTE_WRITE: 2,
-        PTE_EXECUTE: 3,
-        PTE_USER: 4,
-        PTE_GLOBAL: 5,
-        PTE_ACCESSED: 6,
-        PTE_DIRTY: 7,
+/// The page tables allocated at runtime
+pub static PAGE_TABLES: ObjectCache<PageTable> = ObjectCache::new("page_table");
+
+#[repr(align(4096))]
+pub struct PageTable([PageTableEntry; 512]);
+
+impl PageTable {
+    /// Allocate an empty page table from [`PAGE_TABLES`]
+    pub fn allocate() -> Option<&'static mut PageTable> {
+        let pt = PAGE_TABLES.alloc(PageTable([PageTableEntry::EMPTY; 512]))?;
+        Some(unsafe { &mut *pt.as_ptr() })
     }
-}
 
-struct Flags(u8);
+    /// Give a page table obtained from [`PageTable::allocate`] back to [`PAGE_TABLES`]
+    ///
+    /// SAFETY: the table must not be referenced by any other table anymore
+    pub unsafe fn deallocate(&mut self) {
+        assert!(!self.is_static(), "cannot free a static page table");
 
-impl fmt::Debug for Flags {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut flags = f.debug_set();
+        PAGE_TABLES.free(NonNull::from(self));
+    }
 
-        for (flag, name) in FLAGS {
-            if self.0 & (1 << flag) != 0 {
-                flags.entry(&name);
-            }
-        }
+    pub fn is_static(&self) -> bool {
+        self.0[0].rsw() & RSW_STATIC != 0
+    }
 
-        flags.finish()
+    fn ppn(&self) -> u64 {
+        virt_to_phys(self) as u64 >> PAGE_SHIFT
     }
-}
 
-const STATIC_ALLOC: u64 = 1 << 9;
+    fn set(&mut self, idx: u16, value: PageTableEntry) -> Result<(), PtError> {
+        if self[idx].is_valid() {
+            return Err(if !self[idx].is_leaf() {
+                PtError::AlreadMappedIntermediate
+            } else {
+                PtError::AlreadyMappedLeaf
+            });
+        }
 
-#[repr(transparent)]
-pub struct PageTableEntry(u64);
+        value.validate().map_err(PtError::InvalidEntry)?;
 
-impl PageTableEntry {
-    pub fn new(ppn: u64, flags: u8) -> Self {
-        Self::with_data(ppn, false, flags)
-    }
+        let entry = &mut self.0[idx as usize];
+        *entry = value.with_rsw(entry.rsw() | value.rsw());
 
-    pub fn with_data(ppn: u64, data: bool, flags: u8) -> Self {
-        Self(ppn << 10 | (data as u64 & 0b1) << 8 | (flags | PTE_VALID) as u64)
+        Ok(())
     }
 
-    pub fn ppn(&self) -> u64 {
-        self.0 >> 10
+    /// Free every intermediate table below this one and clear all entries
+    ///
+    /// SAFETY: none of the mappings can be in use anymore
+    unsafe fn free_tables(&mut self, level: u8) {
+        for idx in 0..512 {
+            if level > 0 && self[idx].is_valid() && !self[idx].is_leaf() {
+                let next = self.next_table_mut(idx);
+                next.free_tables(level - 1);
+
+                if !next.is_static() {
+                    next.deallocate();
+                }
+            }
+
+            self.clear(idx);
+        }
     }
 
-    pub fn data(&self) -> bool {
-        self.0 >> 8 & 0b1 == 1
+    /// Invalidate an entry, keeping the static allocation marker
+    fn clear(&mut self, idx: u16) {
+        let entry = &mut self.0[idx as usize];
+        *entry = PageTableEntry::EMPTY.with_rsw(entry.rsw() & RSW_STATIC);
     }
 
-    pub fn flags(&self) -> u8 {
-        (self.0 & 0b111) as u8
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|entry| !entry.is_valid())
     }
-}
 
-struct Ppn(u64);
+    /// The table pointed to by the intermediate entry at `idx`
+    fn next_table(&self, idx: u16) -> &PageTable {
+        unsafe { &*self[idx].addr().to_virt().unwrap().as_ptr::<PageTable>() }
+    }
 
-impl fmt::Debug for Ppn {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:#018x}", self.0)
+    fn next_table_mut(&mut self, idx: u16) -> &mut PageTable {
+        unsafe {
+            &mut *self[idx]
+                .addr()
+                .to_virt()
+                .unwrap()
+                .as_mut_ptr::<PageTable>()
+        }
     }
-}
 
-impl fmt::Debug for PageTableEntry {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("PageTableEntry")
-            .field("ppn", &Ppn(self.ppn()))
-            .field("data", &self.data())
-            .field("flags", &Flags(self.flags()))
-            .finish()
+    /// Map a single leaf, allocating the missing intermediate tables.
+    /// `level` is the level of this table.
+    fn map_leaf(
+        &mut self,
+        level: u8,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        page_size: PageSize,
+        flags: PteFlags,
+        memory_type: MemoryType,
+    ) -> Result<(), PtError> {
+        let idx = vpn(virt.as_usize() as u64, level);
+
+        if level == page_size.level() {
+            if self[idx].is_valid() {
+                return Err(PtError::Overlapping);
+            }
+
+            let ppn = (phys.as_usize() >> PAGE_SHIFT) as u64;
+            let entry = PageTableEntry::new(ppn, flags).with_memory_type(memory_type);
+            return self.set(idx, entry);
+        }
+
+        if !self[idx].is_valid() {
+            let table = PageTable::allocate().ok_or(PtError::OutOfMemory)?;
+            self.set(idx, PageTableEntry::new(table.ppn(), PteFlags::empty()))?;
+        } else if self[idx].is_leaf() {
+            return Err(PtError::Overlapping);
+        }
+
+        self.next_table_mut(idx)
+            .map_leaf(level - 1, virt, phys, page_size, flags, memory_type)
     }
-}
 
-#[repr(align(4096))]
-pub struct PageTable([PageTableEntry; 512]);
+    /// Remove the leaf mapping `virt`, freeing intermediate tables left empty.
+    /// Returns the size of the removed leaf.
+    fn unmap_leaf(&mut self, level: u8, virt: VirtAddr) -> Result<PageSize, PtError> {
+        let idx = vpn(virt.as_usize() as u64, level);
 
-impl PageTable {
-    fn ppn(&self) -> u64 {
-        virt_to_phys(self) as u64 >> PAGE_SHIFT
+        if !self[idx].is_valid() {
+            return Err(PtError::NotMapped);
+        }
+
+        if self[idx].is_leaf() {
+            let page_size = PageSize::from_level(level).ok_or(PtError::Misaligned)?;
+            if !virt.as_usize().is_multiple_of(page_size.size()) {
+                return Err(PtError::Misaligned);
+            }
+
+            self.clear(idx);
+            return Ok(page_size);
+        }
+
+        let next = self.next_table_mut(idx);
+        let page_size = next.unmap_leaf(level - 1, virt)?;
+
+        if next.is_empty() && !next.is_static() {
+            unsafe { next.deallocate() };
+            self.clear(idx);
+        }
+
+        Ok(page_size)
     }
 
-    fn set(&mut self, idx: u16, value: PageTableEntry) -> Result<(), PtError> {
-        if self[idx].flags() & PTE_VALID != 0 {
-            return Err(if self[idx].0 >> 1 & 0b111 == 0 {
-                PtError::AlreadMappedIntermediate
-            } else {
-                PtError::AlreadyMappedLeaf
-            });
+    /// Find the leaf entry mapping `virt`, along with its level
+    fn leaf(&self, level: u8, virt: VirtAddr) -> Option<(&PageTableEntry, u8)> {
+        let idx = vpn(virt.as_usize() as u64, level);
+        let entry = &self[idx];
+
+        if !entry.is_valid() {
+            None
+        } else if entry.is_leaf() {
+            Some((entry, level))
+        } else if level == 0 {
+            None
+        } else {
+            self.next_table(idx).leaf(level - 1, virt)
         }
+    }
 
-        self.0[idx as usize].0 |= value.0;
+    fn leaf_mut(&mut self, level: u8, virt: VirtAddr) -> Option<(&mut PageTableEntry, u8)> {
+        let idx = vpn(virt.as_usize() as u64, level);
+
+        if !self[idx].is_valid() {
+            None
+        } else if self[idx].is_leaf() {
+            Some((&mut self.0[idx as usize], level))
+        } else if level == 0 {
+            None
+        } else {
+            self.next_table_mut(idx).leaf_mut(level - 1, virt)
+        }
+    }
 
-        Ok(())
+    /// Call `f` with every valid entry below this table in address order, along with
+    /// the first address it maps and the level of the table holding it.
+    /// `virt` is the first address mapped by this table, of level `level`.
+    fn walk(
+        &self,
+        mode: PagingMode,
+        level: u8,
+        virt: usize,
+        f: &mut impl FnMut(&PageTableEntry, VirtAddr, u8),
+    ) {
+        for idx in 0..512 {
+            let entry = &self[idx];
+
+            if !entry.is_valid() {
+                continue;
+            }
+
+            let entry_virt = virt + ((idx as usize) << (PAGE_SHIFT + 9 * level as usize));
+            f(entry, mode.canonicalize(entry_virt), level);
+
+            if !entry.is_leaf() && level > 0 {
+                self.next_table(idx).walk(mode, level - 1, entry_virt, f);
+            }
+        }
+    }
+
+    /// Size of the region starting at `virt` which is known to be unmapped,
+    /// or `None` if `virt` is mapped.
+    fn unmapped_from(&self, level: u8, virt: VirtAddr) -> Option<usize> {
+        let idx = vpn(virt.as_usize() as u64, level);
+        let entry = &self[idx];
+
+        if !entry.is_valid() {
+            let size = PAGE_SIZE << (9 * level);
+            Some(size - virt.as_usize() % size)
+        } else if entry.is_leaf() || level == 0 {
+            None
+        } else {
+            self.next_table(idx).unmapped_from(level - 1, virt)
+        }
     }
 }
 
@@ -145,7 +269,7 @@ impl fmt::Debug for PageTable {
         let mut pt = f.debug_list();
 
         for entry in &self.0 {
-            if entry.flags() & PTE_VALID != 0 {
+            if entry.is_valid() {
                 pt.entry(&entry);
             }
         }
@@ -155,102 +279,649 @@ impl fmt::Debug for PageTable {
 }
 
 #[derive(Debug)]
-pub struct RootPageTable(PageTable);
+pub struct RootPageTable {
+    table: PageTable,
+    mode: PagingMode,
+}
 
 impl ops::Deref for RootPageTable {
     type Target = PageTable;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.table
     }
 }
 
 impl ops::DerefMut for RootPageTable {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.table
     }
 }
 
-const EMPTY_PTE: PageTableEntry = PageTableEntry(0);
+impl RootPageTable {
+    pub fn mode(&self) -> PagingMode {
+        self.mode
+    }
+
+    /// Value to write to `satp` to use this page table
+    pub fn satp(&self) -> usize {
+        self.mode.satp_mode() << 60 | self.ppn() as usize
+    }
+
+    /// Switch the current hart to this page table.
+    ///
+    /// Returns `false` if the hart does not support the paging mode, in which
+    /// case `satp` is left untouched.
+    ///
+    /// SAFETY: the page table must map the running code, its stack and
+    /// everything the kernel accesses afterwards.
+    pub unsafe fn activate(&self) -> bool {
+        let satp: usize;
+
+        asm!(
+            "csrw satp, {0}",
+            "sfence.vma",
+            "csrr {1}, satp",
+            in(reg) self.satp(),
+            out(reg) satp,
+        );
+
+        satp == self.satp()
+    }
+
+    /// Remove every mapping, free the intermediate tables and switch to `mode`.
+    ///
+    /// SAFETY: the page table must not be in use by any hart
+    pub unsafe fn reset(&mut self, mode: PagingMode) {
+        let top_level = self.mode.top_level();
+        self.table.free_tables(top_level);
+        self.mode = mode;
+    }
+
+    /// Map `size` bytes at `virt` to the physical memory at `phys`.
+    ///
+    /// The range is covered with the largest leaves allowed by the alignment
+    /// of both addresses. Nothing is mapped if the range overlaps an existing
+    /// mapping.
+    pub fn map(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        size: usize,
+        flags: PteFlags,
+    ) -> Result<(), PtError> {
+        self.map_with_memory_type(virt, phys, size, flags, MemoryType::Pma)
+    }
+
+    /// Like [`map`](Self::map), with the given Svpbmt memory type. Anything
+    /// but [`MemoryType::Pma`] requires the Svpbmt extension.
+    pub fn map_with_memory_type(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        size: usize,
+        flags: PteFlags,
+        memory_type: MemoryType,
+    ) -> Result<(), PtError> {
+        self.check_range(virt, size)?;
+
+        if !phys.as_usize().is_multiple_of(PAGE_SIZE) {
+            return Err(PtError::Misaligned);
+        }
+
+        check_leaf_flags(flags)?;
+
+        let mut offset = 0;
+        while offset < size {
+            match self
+                .table
+                .unmapped_from(self.mode.top_level(), virt + offset)
+            {
+                Some(free) => offset += free,
+                None => return Err(PtError::Overlapping),
+            }
+        }
+
+        let mut offset = 0;
+        while offset < size {
+            let (page_virt, page_phys) = (virt + offset, phys + offset);
+
+            let page_size = PageSize::ALL
+                .into_iter()
+                .find(|page_size| {
+                    page_virt.as_usize().is_multiple_of(page_size.size())
+                        && page_phys.as_usize().is_multiple_of(page_size.size())
+                        && offset + page_size.size() <= size
+                })
+                .unwrap();
+
+            if let Err(e) = self.table.map_leaf(
+                self.mode.top_level(),
+                page_virt,
+                page_phys,
+                page_size,
+                flags,
+                memory_type,
+            ) {
+                // Roll back what has been mapped so far
+                let _ = self.unmap(virt, offset);
+                return Err(e);
+            }
+
+            offset += page_size.size();
+        }
+
+        Ok(())
+    }
+
+    /// Remove the mappings of `size` bytes at `virt`.
+    ///
+    /// The range must be fully mapped and must not cover only part of a leaf.
+    pub fn unmap(&mut self, virt: VirtAddr, size: usize) -> Result<(), PtError> {
+        self.check_range(virt, size)?;
+
+        let mut offset = 0;
+        while offset < size {
+            let (_, level) = self
+                .leaf(self.mode.top_level(), virt + offset)
+                .ok_or(PtError::NotMapped)?;
+            let page_size = PageSize::from_level(level).ok_or(PtError::Misaligned)?;
+
+            if !(virt + offset).as_usize().is_multiple_of(page_size.size())
+                || offset + page_size.size() > size
+            {
+                return Err(PtError::Misaligned);
+            }
+
+            offset += page_size.size();
+        }
+
+        let mut offset = 0;
+        while offset < size {
+            let page_size = self
+                .table
+                .unmap_leaf(self.mode.top_level(), virt + offset)?;
+            flush_tlb(virt + offset);
+            offset += page_size.size();
+        }
+
+        Ok(())
+    }
+
+    /// Call `f` with the leaves of the page table in address order, merged
+    /// into the largest [`Mapping`]s
+    pub fn for_each_mapping(&self, mut f: impl FnMut(Mapping)) {
+        let mut current: Option<Mapping> = None;
+
+        self.table.walk(
+            self.mode,
+            self.mode.top_level(),
+            0,
+            &mut |entry, virt, level| {
+                if !entry.is_leaf() {
+                    return;
+                }
+
+                let phys = if entry.is_napot() {
+                    entry.addr() + virt.as_usize() % NAPOT_SIZE
+                } else {
+                    entry.addr()
+                };
+
+                let mapping = Mapping {
+                    virt,
+                    phys,
+                    size: PAGE_SIZE << (9 * level),
+                    flags: entry.flags(),
+                    level,
+                };
+
+                let merged = current
+                    .as_mut()
+                    .is_some_and(|current| current.merge(&mapping));
+
+                if !merged {
+                    if let Some(previous) = current.replace(mapping) {
+                        f(previous);
+                    }
+                }
+            },
+        );
+
+        if let Some(last) = current {
+            f(last);
+        }
+    }
+
+    /// Check the invariants of the page table, calling `report` with every
+    /// violation found. Returns the number of violations.
+    ///
+    /// Tables inside the kernel image must be marked as static, and the
+    /// others, which come from the frame allocator, must not.
+    pub fn check(&self, mut report: impl FnMut(Violation)) -> usize {
+        let mut violations = 0;
+        let mut report = |virt, level, kind| {
+            violations += 1;
+            report(Violation { virt, level, kind });
+        };
+
+        let check_marker = |table: &PageTable| {
+            let in_image = kernel_image().contains(PhysAddr::new(virt_to_phys(table)));
+
+            match (in_image, table.is_static()) {
+                (true, false) => Err(Inconsistency::UnmarkedStaticTable),
+                (false, true) => Err(Inconsistency::MarkedDynamicTable),
+                _ => Ok(()),
+            }
+        };
+
+        if let Err(kind) = check_marker(&self.table) {
+            report(VirtAddr::new(0), self.mode.top_level(), kind);
+        }
+
+        self.table.walk(
+            self.mode,
+            self.mode.top_level(),
+            0,
+            &mut |entry, virt, level| {
+                let result = check_entry(entry, level).and_then(|()| {
+                    if entry.is_leaf() {
+                        return Ok(());
+                    }
+
+                    let table = unsafe { &*entry.addr().to_virt().unwrap().as_ptr::<PageTable>() };
+                    check_marker(table)
+                });
+
+                if let Err(kind) = result {
+                    report(virt, level, kind);
+                }
+            },
+        );
+
+        // W^X must also hold across aliases of the same memory
+        self.for_each_mapping(|code| {
+            if !code.flags.contains(PTE_EXECUTE) {
+                return;
+            }
+
+            self.for_each_mapping(|data| {
+                if data.flags.contains(PTE_WRITE)
+                    && data.phys < code.phys_end()
+                    && code.phys < data.phys_end()
+                {
+                    report(data.virt, data.level, Inconsistency::WritableCodeAlias);
+                }
+            });
+        });
+
+        violations
+    }
+
+    /// Look up the physical address mapped at `virt`
+    pub fn translate(&self, virt: VirtAddr) -> Result<Translation, PtError> {
+        if !self.mode.is_canonical(virt) {
+            return Err(PtError::NonCanonical);
+        }
+
+        let (entry, level) = self
+            .leaf(self.mode.top_level(), virt)
+            .ok_or(PtError::NotMapped)?;
+        let page_size = PageSize::from_level(level).ok_or(PtError::Misaligned)?;
+
+        // A NAPOT leaf is one of the 4 KiB entries mapping a contiguous 64 KiB page
+        let mapping_size = if entry.is_napot() {
+            NAPOT_SIZE
+        } else {
+            page_size.size()
+        };
+
+        Ok(Translation {
+            phys: entry.addr() + virt.as_usize() % mapping_size,
+            flags: entry.flags(),
+            page_size,
+        })
+    }
+
+    /// Replace the permissions of the leaves mapping `size` bytes at `virt`
+    pub fn update_flags(
+        &mut self,
+        virt: VirtAddr,
+        size: usize,
+        flags: PteFlags,
+    ) -> Result<(), PtError> {
+        self.check_range(virt, size)?;
+        check_leaf_flags(flags)?;
+
+        let mut offset = 0;
+        while offset < size {
+            let translation = self.translate(virt + offset)?;
+            let page_size = translation.page_size.size();
+
+            if !(virt + offset).as_usize().is_multiple_of(page_size) || offset + page_size > size {
+                return Err(PtError::Misaligned);
+            }
+
+            offset += page_size;
+        }
+
+        let mut offset = 0;
+        while offset < size {
+            let (entry, level) = self
+                .table
+                .leaf_mut(self.mode.top_level(), virt + offset)
+                .unwrap();
+
+            *entry = entry.with_flags(flags | PTE_VALID);
+            flush_tlb(virt + offset);
+
+            offset += PAGE_SIZE << (9 * level);
+        }
+
+        Ok(())
+    }
+}
+
+impl RootPageTable {
+    fn check_range(&self, virt: VirtAddr, size: usize) -> Result<(), PtError> {
+        if !virt.as_usize().is_multiple_of(PAGE_SIZE) || !size.is_multiple_of(PAGE_SIZE) {
+            Err(PtError::Misaligned)
+        } else if !self.mode.is_canonical(virt)
+            || size > 0 && !self.mode.is_canonical(virt + (size - 1))
+        {
+            Err(PtError::NonCanonical)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A leaf must be at least readable or executable, and not use a reserved encoding
+fn check_leaf_flags(flags: PteFlags) -> Result<(), PtError> {
+    if !flags.intersects(PTE_READ | PTE_EXECUTE) {
+        return Err(PtError::InvalidFlags);
+    }
+
+    PageTableEntry::new(0, flags)
+        .validate()
+        .map_err(PtError::InvalidEntry)
+}
+
+/// Invalidate the TLB entries for the page containing `virt`
+fn flush_tlb(virt: VirtAddr) {
+    unsafe { asm!("sfence.vma {0}, zero", in(reg) virt.as_usize()) }
+}
 
 const EMPTY_STATIC_PT: PageTable = {
-    let mut pt = PageTable([EMPTY_PTE; 512]);
-    pt.0[0].0 |= STATIC_ALLOC;
+    let mut pt = PageTable([PageTableEntry::EMPTY; 512]);
+    pt.0[0] = PageTableEntry::EMPTY.with_rsw(RSW_STATIC);
     pt
 };
 
-fn vpn(addr: u64, idx: u8) -> u16 {
-    ((addr >> (12 + 9 * idx)) & ((1 << 9) - 1)) as u16
+/// Root of the kernel address space.
+///
+/// Always locked with interrupts disabled: interrupt handlers may grow the
+/// heap, which maps frames. For the same reason nothing may be allocated from
+/// the heap while it is held. Lock order: the growth lock of the heap, then
+/// this one; the heap lock itself is never held while taking it.
+pub static KERNEL_PAGE_TABLE: SpinLock<RootPageTable> = SpinLock::new(RootPageTable {
+    table: EMPTY_STATIC_PT,
+    mode: PagingMode::Sv39,
+});
+
+/// Build the kernel page table and switch to it.
+///
+/// The paging mode comes from the `mmu-type` of the harts. If it is missing,
+/// or if the hart refuses it, the next smaller mode is tried: writing an
+/// unsupported mode to `satp` has no effect.
+///
+/// Intermediate tables come from the frame allocator, which must be initialized.
+pub fn init(boot_info: &BootInfo) {
+    without_interrupts(|| init_kernel_page_table(boot_info));
+    direct_map::activate(&boot_info.memory_map);
+    check_kernel_page_table();
 }
 
-/// Level 2 page table
-/// SAFETY: must be modified with the lock for KERNEL_PAGE_TABLE
-static mut HIGH_PT: PageTable = EMPTY_STATIC_PT;
+fn init_kernel_page_table(boot_info: &BootInfo) {
+    let mut root_pt = KERNEL_PAGE_TABLE.lock();
 
-/// Level 1 page table
-/// SAFETY: must be modified with the lock for KERNEL_PAGE_TABLE
-static mut STACK_PT: PageTable = EMPTY_STATIC_PT;
+    let advertised = PagingMode::from_fdt(&boot_info.fdt);
+    let candidates = PagingMode::ALL
+        .into_iter()
+        .filter(|&mode| advertised.is_none_or(|advertised| mode <= advertised));
 
-/// Level 3 page table
-pub static KERNEL_PAGE_TABLE: SpinLock<RootPageTable> =
-    SpinLock::new(RootPageTable(EMPTY_STATIC_PT));
+    for mode in candidates {
+        unsafe { root_pt.reset(mode) };
+        map_kernel(&mut root_pt, &boot_info.memory_map);
 
-pub fn init() {
-    let mut root_pt = KERNEL_PAGE_TABLE.lock();
-    let high_pt = unsafe { &mut *core::ptr::addr_of_mut!(HIGH_PT) };
-    let stack_pt = unsafe { &mut *core::ptr::addr_of_mut!(STACK_PT) };
+        if unsafe { root_pt.activate() } {
+            return;
+        }
+    }
 
-    let virtual_code_start = unsafe { &KERNEL_CODE_VIRTUAL as *const _ as u64 };
-    let virtual_stack = unsafe { &KERNEL_STACK_VIRTUAL as *const _ as u64 };
+    panic!("no supported paging mode");
+}
 
-    assert_eq!(
-        vpn(virtual_code_start, 3),
-        vpn(virtual_stack, 3),
-        "Stack & code must share the same level 3"
-    );
+/// Log the kernel address space at debug level, and the violations of its
+/// invariants as errors. Returns the number of violations.
+pub fn check_kernel_page_table() -> usize {
+    without_interrupts(|| {
+        let root_pt = KERNEL_PAGE_TABLE.lock();
 
-    root_pt
-        .set(
-            vpn(virtual_code_start, 3),
-            PageTableEntry::new(high_pt.ppn(), PTE_VALID),
-        )
-        .unwrap();
+        if log::log_enabled!(log::Level::Debug) {
+            debug!("kernel address space ({}):", root_pt.mode());
+            root_pt.for_each_mapping(|mapping| debug!("  {mapping}"));
+        }
+
+        root_pt.check(|violation| error!("kernel page table: {violation}"))
+    })
+}
 
+/// Map the kernel image with W^X permissions, and the direct map.
+///
+/// The whole RAM window is mapped RW, except for the code which is RX and the
+/// read-only data which is R. The window includes `.data` and `.bss`, the rest
+/// of the RAM is reached through the direct map, where the code and read-only
+/// data are R.
+fn map_kernel(root_pt: &mut RootPageTable, memory_map: &MemoryMap) {
+    let symbol = |sym: &u8| VirtAddr::new(sym as *const _ as usize);
+    let to_phys = |virt: VirtAddr| virt.to_phys().unwrap();
+
+    // The window ends at the top of the address space, its end is not representable
+    let window_start = unsafe { symbol(&KERNEL_CODE_VIRTUAL) };
+    let text_start = unsafe { symbol(&KERNEL_TEXT_START) };
+    let rodata_start = unsafe { symbol(&KERNEL_RODATA_START) };
+    let data_start = unsafe { symbol(&KERNEL_DATA_START) };
+    let virtual_stack = unsafe { symbol(&KERNEL_STACK_VIRTUAL) };
+
+    let sections = [
+        // Firmware and boot code
+        (
+            window_start,
+            text_start - window_start,
+            PTE_READ | PTE_WRITE,
+        ),
+        (
+            text_start,
+            rodata_start - text_start,
+            PTE_READ | PTE_EXECUTE,
+        ),
+        (rodata_start, data_start - rodata_start, PTE_READ),
+        // .data, .bss and the rest of the RAM
+        (
+            data_start,
+            RAM_WINDOW_SIZE - (data_start - window_start),
+            PTE_READ | PTE_WRITE,
+        ),
+    ];
+
+    for (start, size, flags) in sections {
+        root_pt.map(start, to_phys(start), size, flags).unwrap();
+    }
+
+    // The bottom of the stack is left unmapped as a guard
     root_pt
-        .set(
-            vpn(RAM_VIRTUAL_START, 3),
-            PageTableEntry::new(0, PTE_READ | PTE_WRITE),
+        .map(
+            virtual_stack + STACK_GUARD_SIZE,
+            PhysAddr::new(PHYSICAL_STACK_START + STACK_GUARD_SIZE),
+            STACK_LEN - STACK_GUARD_SIZE,
+            PTE_READ | PTE_WRITE,
         )
         .unwrap();
 
-    high_pt
-        .set(
-            vpn(virtual_code_start, 2),
-            PageTableEntry::new(
-                RAM_START as u64 >> PAGE_SHIFT,
-                PTE_EXECUTE | PTE_READ | PTE_WRITE,
-            ),
-        )
-        .unwrap();
+    let read_only = Region::new(to_phys(text_start), data_start - text_start);
+    direct_map::map(root_pt, memory_map, read_only).unwrap();
+}
 
-    high_pt
-        .set(
-            vpn(virtual_stack, 2),
-            PageTableEntry::new(stack_pt.ppn(), 0),
-        )
-        .unwrap();
+#[cfg(test)]
+mod tests {
+    use dante_core::page_table::PteError;
 
-    stack_pt
-        .set(
-            vpn(virtual_stack, 1),
-            PageTableEntry::new(
-                PHYSICAL_STACK_START as u64 >> PAGE_SHIFT,
-                PTE_READ | PTE_WRITE,
-            ),
-        )
-        .unwrap();
+    use super::*;
+    use crate::memory::ioremap::{IOREMAP_SIZE, IOREMAP_START};
+    use crate::memory::{alloc_frames, free_frames};
+
+    /// Unused range between the ioremap window and the boot stack
+    const TEST_VIRT: usize = IOREMAP_START + IOREMAP_SIZE;
+
+    #[test_case]
+    fn map_translate_unmap() {
+        let frame = alloc_frames(0).unwrap();
+        let virt = VirtAddr::new(TEST_VIRT);
+        let mut root_pt = KERNEL_PAGE_TABLE.lock();
+
+        root_pt
+            .map(virt, frame, PAGE_SIZE, PTE_READ | PTE_WRITE)
+            .unwrap();
 
-    // crate::dbg!(&root_pt, &high_pt, &stack_pt);
+        let translation = root_pt.translate(virt + 0x10).unwrap();
+        assert_eq!(translation.phys, frame + 0x10);
+        assert_eq!(translation.page_size, PageSize::Size4K);
+
+        // Writes through the new mapping are visible through the direct map
+        unsafe { virt.as_mut_ptr::<u64>().write_volatile(0xdead_beef) };
+        assert_eq!(
+            unsafe { frame.to_virt().unwrap().as_ptr::<u64>().read() },
+            0xdead_beef
+        );
+
+        root_pt.unmap(virt, PAGE_SIZE).unwrap();
+        assert_eq!(root_pt.translate(virt).unwrap_err(), PtError::NotMapped);
+
+        drop(root_pt);
+        free_frames(frame, 0);
+    }
+
+    #[test_case]
+    fn map_errors() {
+        let frame = alloc_frames(0).unwrap();
+        let virt = VirtAddr::new(TEST_VIRT);
+        let mut root_pt = KERNEL_PAGE_TABLE.lock();
+
+        assert_eq!(
+            root_pt.map(virt + 8, frame, PAGE_SIZE, PTE_READ),
+            Err(PtError::Misaligned)
+        );
+        assert_eq!(
+            root_pt.map(virt, frame, PAGE_SIZE, PTE_WRITE),
+            Err(PtError::InvalidFlags)
+        );
+        assert_eq!(
+            root_pt.map(virt, frame, PAGE_SIZE, PTE_WRITE | PTE_EXECUTE),
+            Err(PtError::InvalidEntry(PteError::WriteWithoutRead))
+        );
+        assert_eq!(root_pt.unmap(virt, PAGE_SIZE), Err(PtError::NotMapped));
+
+        root_pt.map(virt, frame, PAGE_SIZE, PTE_READ).unwrap();
+        assert_eq!(
+            root_pt.map(virt, frame, PAGE_SIZE, PTE_READ),
+            Err(PtError::Overlapping)
+        );
+        root_pt.unmap(virt, PAGE_SIZE).unwrap();
+
+        drop(root_pt);
+        free_frames(frame, 0);
+    }
 
-    unsafe { asm!("csrw satp, {0}", in(reg) 9 << 60 | root_pt.ppn()) }
+    #[test_case]
+    fn map_uses_large_pages() {
+        let order = 9;
+        let frames = alloc_frames(order).unwrap();
+        let virt = VirtAddr::new(TEST_VIRT);
+        let size = PageSize::Size2M.size();
+        let mut root_pt = KERNEL_PAGE_TABLE.lock();
+
+        root_pt.map(virt, frames, size, PTE_READ).unwrap();
+        assert_eq!(root_pt.translate(virt).unwrap().page_size, PageSize::Size2M);
+
+        // A 2 MiB leaf cannot be split
+        assert_eq!(root_pt.unmap(virt, PAGE_SIZE), Err(PtError::Misaligned));
+        root_pt.unmap(virt, size).unwrap();
+
+        drop(root_pt);
+        free_frames(frames, order);
+    }
+
+    #[test_case]
+    fn update_flags() {
+        let frame = alloc_frames(0).unwrap();
+        let virt = VirtAddr::new(TEST_VIRT);
+        let mut root_pt = KERNEL_PAGE_TABLE.lock();
+
+        root_pt
+            .map(virt, frame, PAGE_SIZE, PTE_READ | PTE_WRITE)
+            .unwrap();
+        root_pt.update_flags(virt, PAGE_SIZE, PTE_READ).unwrap();
+
+        let flags = root_pt.translate(virt).unwrap().flags;
+        assert_eq!(flags & (PTE_READ | PTE_WRITE), PTE_READ);
+
+        root_pt.unmap(virt, PAGE_SIZE).unwrap();
+
+        drop(root_pt);
+        free_frames(frame, 0);
+    }
+
+    #[test_case]
+    fn mappings_are_merged() {
+        let frames = alloc_frames(1).unwrap();
+        let virt = VirtAddr::new(TEST_VIRT);
+        let mut root_pt = KERNEL_PAGE_TABLE.lock();
+
+        root_pt.map(virt, frames, 2 * PAGE_SIZE, PTE_READ).unwrap();
+
+        let mut mapping = None;
+        root_pt.for_each_mapping(|m| {
+            if m.virt == virt {
+                mapping = Some(m);
+            }
+        });
+
+        assert_eq!(
+            mapping.map(|m| (m.phys, m.size, m.level)),
+            Some((frames, 2 * PAGE_SIZE, 0))
+        );
+
+        root_pt.unmap(virt, 2 * PAGE_SIZE).unwrap();
+
+        drop(root_pt);
+        free_frames(frames, 1);
+    }
+
+    #[test_case]
+    fn kernel_page_table_is_consistent() {
+        let violations = KERNEL_PAGE_TABLE
+            .lock()
+            .check(|violation| crate::debug_println!("{violation}"));
+
+        assert_eq!(violations, 0);
+    }
+
+    #[test_case]
+    fn kernel_text_is_not_writable() {
+        let text = VirtAddr::new(flush_tlb as *const () as usize);
+        let flags = KERNEL_PAGE_TABLE.lock().translate(text).unwrap().flags;
+
+        assert_eq!(flags & (PTE_WRITE | PTE_EXECUTE), PTE_EXECUTE);
+    }
 }
@@ -0,0 +1,240 @@
+use core::arch::asm;
+
+use fdt::node::FdtNode;
+use fdt::Fdt;
+use log::warn;
+use spinning_top::Spinlock as SpinLock;
+
+use crate::arch::{without_interrupts, MAX_HARTS};
+use crate::boot::{boot_info, BootInfo};
+use crate::hart;
+use crate::memory::ioremap::ioremap;
+use crate::memory::PhysAddr;
+use crate::register_block;
+
+/// The PLIC supports at most 1023 interrupt sources, source 0 does not exist
+pub const MAX_SOURCES: usize = 1024;
+
+/// Highest priority supported by every PLIC. 0 means "never interrupt".
+pub const MAX_PRIORITY: u32 = 7;
+
+/// `sie.SEIE`: supervisor external interrupt enabled
+const SIE_SEIE: usize = 1 << 9;
+
+/// Interrupt number of the supervisor external interrupt in `interrupts-extended`
+const IRQ_S_EXT: u32 = 9;
+
+/// The PLIC supports at most 15872 contexts
+const MAX_CONTEXTS: usize = 15872;
+
+register_block! {
+    /// Registers of a PLIC, as laid out by the RISC-V PLIC specification
+    struct Registers {
+        /// Priority of each source
+        0x0 => priority[MAX_SOURCES]: u32, ReadWrite;
+        /// Enable bits of the sources, 32 words per context
+        0x2000 => enable[MAX_CONTEXTS * 32]: u32, ReadWrite;
+        /// Priority threshold of each context
+        0x20_0000 => threshold[MAX_CONTEXTS; 0x1000]: u32, ReadWrite;
+        /// Claim (read) and completion (write) of each context
+        0x20_0004 => claim[MAX_CONTEXTS; 0x1000]: u32, ReadWrite;
+    }
+}
+
+pub type IrqHandler = fn(u32);
+
+static PLIC: SpinLock<Option<Plic>> = SpinLock::new(None);
+
+static HANDLERS: SpinLock<[Option<IrqHandler>; MAX_SOURCES]> = SpinLock::new([None; MAX_SOURCES]);
+
+#[derive(Copy, Clone, Debug)]
+pub struct Plic {
+    registers: Registers,
+    phandle: u32,
+    /// Number of interrupt sources, numbered from 1
+    sources: u32,
+    /// Supervisor context of each hart
+    contexts: [Option<u32>; MAX_HARTS],
+}
+
+impl Plic {
+    fn from_fdt(fdt: &Fdt<'_>) -> Option<Self> {
+        let node = fdt.find_compatible(&["riscv,plic0", "sifive,plic-1.0.0"])?;
+
+        let reg = node.reg()?.next()?;
+        let io = ioremap(PhysAddr::new(reg.starting_address as usize), reg.size?)
+            .map_err(|err| warn!("PLIC registers: {err}"))
+            .ok()?;
+
+        let phandle = node.property("phandle")?.as_usize()? as u32;
+        let sources = node.property("riscv,ndev")?.as_usize()? as u32;
+
+        // Each (interrupt controller, interrupt) pair of `interrupts-extended`
+        // is a context, in order. Keep the supervisor ones, per hart.
+        let mut contexts = [None; MAX_HARTS];
+
+        let cells = node.property("interrupts-extended")?.value;
+        for (context, pair) in cells.chunks_exact(8).enumerate() {
+            let intc = u32::from_be_bytes(pair[0..4].try_into().unwrap());
+            let irq = u32::from_be_bytes(pair[4..8].try_into().unwrap());
+
+            if irq != IRQ_S_EXT {
+                continue;
+            }
+
+            if let Some(hart) = hart_of_intc(fdt, intc).filter(|&hart| hart < MAX_HARTS) {
+                contexts[hart] = Some(context as u32);
+            }
+        }
+
+        Some(Self {
+            registers: Registers::new(io),
+            phandle,
+            sources,
+            contexts,
+        })
+    }
+
+    fn set_priority(&self, irq: u32, priority: u32) {
+        self.registers.priority(irq as usize).write(priority);
+    }
+
+    fn set_enabled(&self, context: u32, irq: u32, enabled: bool) {
+        let word = self
+            .registers
+            .enable(32 * context as usize + irq as usize / 32);
+        let bit = 1 << (irq % 32);
+
+        word.modify(|word| if enabled { word | bit } else { word & !bit });
+    }
+
+    fn set_threshold(&self, context: u32, threshold: u32) {
+        self.registers.threshold(context as usize).write(threshold);
+    }
+
+    fn claim(&self, context: u32) -> u32 {
+        self.registers.claim(context as usize).read()
+    }
+
+    fn complete(&self, context: u32, irq: u32) {
+        self.registers.claim(context as usize).write(irq);
+    }
+
+    pub fn sources(&self) -> u32 {
+        self.sources
+    }
+
+    pub fn context(&self, hart_id: usize) -> Option<u32> {
+        self.contexts.get(hart_id).copied().flatten()
+    }
+}
+
+/// Hart id of the `cpu` node owning the interrupt controller with `phandle`
+fn hart_of_intc(fdt: &Fdt<'_>, phandle: u32) -> Option<usize> {
+    let is_intc = |node: &FdtNode<'_, '_>| {
+        node.name == "interrupt-controller"
+            && node.property("phandle").and_then(|p| p.as_usize()) == Some(phandle as usize)
+    };
+
+    fdt.find_node("/cpus")?
+        .children()
+        .find(|cpu| cpu.children().any(|node| is_intc(&node)))?
+        .property("reg")?
+        .as_usize()
+}
+
+/// Discover the PLIC and route its interrupts to the supervisor context of the boot hart
+pub fn init(boot_info: &BootInfo) {
+    let Some(plic) = Plic::from_fdt(&boot_info.fdt) else {
+        return;
+    };
+
+    let context = plic
+        .context(boot_info.hart_id)
+        .expect("no PLIC context for the boot hart");
+
+    // Mask every source until a driver registers a handler
+    for irq in 1..=plic.sources {
+        plic.set_priority(irq, 0);
+        plic.set_enabled(context, irq, false);
+    }
+
+    plic.set_threshold(context, 0);
+    without_interrupts(|| *PLIC.lock() = Some(plic));
+
+    unsafe { asm!("csrs sie, {0}", in(reg) SIE_SEIE) };
+}
+
+pub fn plic() -> Option<Plic> {
+    without_interrupts(|| *PLIC.lock())
+}
+
+/// Interrupt number of a device node, if it is wired to the PLIC
+pub fn irq_of(node: &FdtNode<'_, '_>) -> Option<u32> {
+    let plic = plic()?;
+    let parent = node.property("interrupt-parent")?.as_usize()?;
+
+    if parent != plic.phandle as usize {
+        return None;
+    }
+
+    node.interrupts()?.next().map(|irq| irq as u32)
+}
+
+/// Call `handler` on the boot hart whenever `irq` fires, at the given priority (1 to 7)
+pub fn register(irq: u32, priority: u32, handler: IrqHandler) {
+    let plic = plic().expect("PLIC not initialized");
+    let boot_hart = boot_info().hart_id;
+    let context = plic.context(boot_hart).unwrap();
+
+    assert!(irq > 0 && irq <= plic.sources, "invalid IRQ {irq}");
+    assert!(
+        priority > 0 && priority <= MAX_PRIORITY,
+        "invalid priority {priority}"
+    );
+
+    without_interrupts(|| HANDLERS.lock()[irq as usize] = Some(handler));
+
+    plic.set_priority(irq, priority);
+    plic.set_enabled(context, irq, true);
+}
+
+/// Stop delivering `irq` and forget its handler
+pub fn unregister(irq: u32) {
+    let plic = plic().expect("PLIC not initialized");
+    let boot_hart = boot_info().hart_id;
+    let context = plic.context(boot_hart).unwrap();
+
+    plic.set_enabled(context, irq, false);
+    plic.set_priority(irq, 0);
+
+    without_interrupts(|| HANDLERS.lock()[irq as usize] = None);
+}
+
+/// Called from the trap handler on supervisor external interrupts:
+/// claim and handle every pending interrupt of the current hart's context.
+pub fn handle_external_interrupt() {
+    let Some(plic) = plic() else {
+        return;
+    };
+
+    let Some(context) = plic.context(hart::id()) else {
+        return;
+    };
+
+    loop {
+        let irq = plic.claim(context);
+        if irq == 0 {
+            break;
+        }
+
+        let handler = HANDLERS.lock()[irq as usize];
+
+        match handler {
+            Some(handler) => handler(irq),
+            None => warn!("spurious interrupt {irq}"),
+        }
+
+        plic.complete(context, irq);
+    }
+}
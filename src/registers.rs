@@ -0,0 +1,411 @@
+//! Typed access to blocks of device registers mapped by `ioremap`.
+//!
+//! [`register_block!`](crate::register_block) declares the registers of a
+//! device: their offsets, widths, whether they are readable or writable, and
+//! their bitfields. Each register is an accessor method of the block,
+//! returning a [`Register`], and its fields are constants of a module named
+//! after it:
+//!
+//! ```ignore
+//! register_block! {
+//!     /// Registers of a device
+//!     pub struct DeviceRegisters {
+//!         /// Control register
+//!         0x0 => control: u32, ReadWrite {
+//!             ENABLE: 0,
+//!             MODE: 4..6,
+//!         };
+//!         /// One status word per channel
+//!         0x100 => status[8]: u32, ReadOnly;
+//!         /// One doorbell per channel, each in its own page
+//!         0x1000 => doorbell[8; 0x1000]: u32, WriteOnly;
+//!     }
+//! }
+//!
+//! regs.control().write(control::MODE.val(2));
+//! regs.control().set(control::ENABLE);
+//! let status = regs.status(3).read();
+//! ```
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::size_of;
+
+use crate::memory::ioremap::{IoMem, IoValue};
+
+/// Access marker of the registers which can only be read
+pub struct ReadOnly;
+/// Access marker of the registers which can only be written
+pub struct WriteOnly;
+/// Access marker of the registers which can be read, written and modified
+pub struct ReadWrite;
+
+pub trait Readable {}
+pub trait Writable {}
+
+impl Readable for ReadOnly {}
+impl Readable for ReadWrite {}
+impl Writable for WriteOnly {}
+impl Writable for ReadWrite {}
+
+/// How the declared offsets and widths of a block map to bus accesses.
+///
+/// Some devices, e.g. 16550 UARTs, have their registers spread out and
+/// accessed with wider loads and stores depending on the board.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// The declared offsets are multiplied by `1 << shift`
+    pub shift: u32,
+    /// Width of every access in bytes, instead of the declared width
+    pub io_width: Option<usize>,
+}
+
+impl Layout {
+    /// The offsets and widths as declared
+    pub const NATURAL: Layout = Layout {
+        shift: 0,
+        io_width: None,
+    };
+}
+
+/// Bits `shift..shift + width` of a register of type `T`
+pub struct Field<T> {
+    shift: u32,
+    width: u32,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Clone for Field<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Field<T> {}
+
+impl<T> fmt::Debug for Field<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Field({}..{})", self.shift, self.shift + self.width)
+    }
+}
+
+impl<T: IoValue> Field<T> {
+    pub const fn new(shift: u32, width: u32) -> Self {
+        assert!(width > 0 && shift + width <= 8 * size_of::<T>() as u32);
+
+        Self {
+            shift,
+            width,
+            _marker: PhantomData,
+        }
+    }
+
+    fn bits_mask(self) -> u64 {
+        u64::MAX >> (64 - self.width) << self.shift
+    }
+
+    /// The bits of the field set, all others clear
+    pub fn mask(self) -> T {
+        T::from_bits(self.bits_mask())
+    }
+
+    /// `value` moved to the bits of the field, which it must fit in
+    pub fn val(self, value: T) -> T {
+        let value = value.into_bits();
+        // Checked before shifting, the bits shifted out of a u64 would be lost
+        assert!(
+            self.width == 64 || value >> self.width == 0,
+            "{value:#x} does not fit in {self:?}"
+        );
+
+        T::from_bits(value << self.shift)
+    }
+
+    /// The value of the field in `register`
+    pub fn get(self, register: T) -> T {
+        T::from_bits((register.into_bits() & self.bits_mask()) >> self.shift)
+    }
+}
+
+/// A register of a block declared with [`register_block!`](crate::register_block),
+/// holding values of type `T`
+pub struct Register<'a, T, A> {
+    io: &'a IoMem,
+    offset: usize,
+    io_width: Option<usize>,
+    _marker: PhantomData<(T, A)>,
+}
+
+impl<'a, T: IoValue, A> Register<'a, T, A> {
+    /// The register declared at `offset` of a block
+    pub fn new(io: &'a IoMem, offset: usize, layout: Layout) -> Self {
+        Self {
+            io,
+            offset: offset << layout.shift,
+            io_width: layout.io_width,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Offset of the register in the [`IoMem`] of its block
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Width of every access to the register in bytes
+    pub fn width(&self) -> usize {
+        self.io_width.unwrap_or(size_of::<T>())
+    }
+}
+
+impl<T: IoValue, A: Readable> Register<'_, T, A> {
+    pub fn read(&self) -> T {
+        let bits = match self.width() {
+            1 => self.io.read::<u8>(self.offset).into_bits(),
+            2 => self.io.read::<u16>(self.offset).into_bits(),
+            4 => self.io.read::<u32>(self.offset).into_bits(),
+            8 => self.io.read::<u64>(self.offset),
+            width => panic!("invalid register width {width}"),
+        };
+
+        T::from_bits(bits)
+    }
+
+    pub fn read_field(&self, field: Field<T>) -> T {
+        field.get(self.read())
+    }
+
+    /// Whether any bit of `field` is set
+    pub fn is_set(&self, field: Field<T>) -> bool {
+        self.read().into_bits() & field.bits_mask() != 0
+    }
+}
+
+impl<T: IoValue, A: Writable> Register<'_, T, A> {
+    pub fn write(&self, value: T) {
+        let bits = value.into_bits();
+
+        match self.width() {
+            1 => self.io.write(self.offset, u8::from_bits(bits)),
+            2 => self.io.write(self.offset, u16::from_bits(bits)),
+            4 => self.io.write(self.offset, u32::from_bits(bits)),
+            8 => self.io.write(self.offset, bits),
+            width => panic!("invalid register width {width}"),
+        }
+    }
+}
+
+impl<T: IoValue> Register<'_, T, ReadWrite> {
+    /// Read the register, and write back the result of `f`
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+
+    /// Set every bit of `field`
+    pub fn set(&self, field: Field<T>) {
+        self.modify(|value| T::from_bits(value.into_bits() | field.bits_mask()));
+    }
+
+    /// Clear every bit of `field`
+    pub fn clear(&self, field: Field<T>) {
+        self.modify(|value| T::from_bits(value.into_bits() & !field.bits_mask()));
+    }
+
+    /// Replace the bits of `field` with `value`, keeping the others
+    pub fn write_field(&self, field: Field<T>, value: T) {
+        let value = field.val(value).into_bits();
+        self.modify(|old| T::from_bits(old.into_bits() & !field.bits_mask() | value));
+    }
+}
+
+/// Declared offset of register `index` of an array of `count` registers at
+/// `offset`, `None` past the end of the array
+pub fn array_offset(offset: usize, index: usize, count: usize, stride: usize) -> Option<usize> {
+    (index < count).then(|| offset + index * stride)
+}
+
+/// Declare a block of device registers, see the [module documentation](crate::registers)
+#[macro_export]
+macro_rules! register_block {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $block:ident {
+            $(
+                $(#[$reg_meta:meta])*
+                $offset:literal => $reg:ident $([$count:expr $(; $stride:expr)?])? : $ty:ty, $access:ident
+                $({
+                    $(
+                        $(#[$field_meta:meta])*
+                        $field:ident : $low:literal $(.. $high:literal)?
+                    ),* $(,)?
+                })?;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, Debug)]
+        $vis struct $block {
+            io: $crate::memory::ioremap::IoMem,
+            layout: $crate::registers::Layout,
+        }
+
+        impl $block {
+            /// The registers at the offsets and widths they are declared with
+            pub fn new(io: $crate::memory::ioremap::IoMem) -> Self {
+                Self::with_layout(io, $crate::registers::Layout::NATURAL)
+            }
+
+            pub fn with_layout(
+                io: $crate::memory::ioremap::IoMem,
+                layout: $crate::registers::Layout,
+            ) -> Self {
+                Self { io, layout }
+            }
+
+            pub fn io(&self) -> $crate::memory::ioremap::IoMem {
+                self.io
+            }
+
+            $(
+                $crate::register_block!(
+                    @accessor
+                    $(#[$reg_meta])*
+                    $reg, $offset, $ty, $access, [$($count $(; $stride)?)?]
+                );
+            )*
+        }
+
+        $($(
+            #[doc = concat!("Fields of `", stringify!($reg), "`")]
+            $vis mod $reg {
+                $(
+                    $(#[$field_meta])*
+                    pub const $field: $crate::registers::Field<$ty> =
+                        $crate::register_block!(@field $ty, $low $(.. $high)?);
+                )*
+            }
+        )?)*
+    };
+
+    (@accessor $(#[$meta:meta])* $reg:ident, $offset:expr, $ty:ty, $access:ident, []) => {
+        $(#[$meta])*
+        pub fn $reg(&self) -> $crate::registers::Register<'_, $ty, $crate::registers::$access> {
+            $crate::registers::Register::new(&self.io, $offset, self.layout)
+        }
+    };
+
+    (@accessor $(#[$meta:meta])* $reg:ident, $offset:expr, $ty:ty, $access:ident, [$count:expr]) => {
+        $crate::register_block!(
+            @accessor $(#[$meta])* $reg, $offset, $ty, $access,
+            [$count; core::mem::size_of::<$ty>()]
+        );
+    };
+
+    (@accessor $(#[$meta:meta])* $reg:ident, $offset:expr, $ty:ty, $access:ident, [$count:expr; $stride:expr]) => {
+        $(#[$meta])*
+        pub fn $reg(
+            &self,
+            index: usize,
+        ) -> $crate::registers::Register<'_, $ty, $crate::registers::$access> {
+            let offset = $crate::registers::array_offset($offset, index, $count, $stride)
+                .unwrap_or_else(|| panic!(concat!("no ", stringify!($reg), " register {}"), index));
+
+            $crate::registers::Register::new(&self.io, offset, self.layout)
+        }
+    };
+
+    (@field $ty:ty, $bit:literal) => {
+        $crate::registers::Field::<$ty>::new($bit, 1)
+    };
+
+    (@field $ty:ty, $low:literal .. $high:literal) => {
+        $crate::registers::Field::<$ty>::new($low, $high - $low)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::ioremap::{ioremap, iounmap};
+    use crate::memory::PhysAddr;
+
+    /// Unused on QEMU virt, only the offsets of the registers are looked at
+    const TEST_PHYS: usize = 0x7000_0000;
+
+    register_block! {
+        struct TestRegisters {
+            0x0 => control: u8, ReadWrite {
+                ENABLE: 0,
+            };
+            0x4 => status[4]: u32, ReadOnly;
+            0x100 => doorbell[2; 0x10]: u16, WriteOnly;
+        }
+    }
+
+    const WORD_LENGTH: Field<u8> = Field::new(0, 2);
+    const DLAB: Field<u8> = Field::new(7, 1);
+
+    #[test_case]
+    fn field_mask_val_get() {
+        assert_eq!(WORD_LENGTH.mask(), 0b11);
+        assert_eq!(DLAB.mask(), 0x80);
+
+        assert_eq!(WORD_LENGTH.val(0b10) | DLAB.val(1), 0x82);
+        assert_eq!(WORD_LENGTH.get(0x82), 0b10);
+        assert_eq!(DLAB.get(0x82), 1);
+
+        let high = Field::<u64>::new(60, 4);
+        assert_eq!(high.mask(), 0xf000_0000_0000_0000);
+        assert_eq!(high.val(0xa), 0xa000_0000_0000_0000);
+        assert_eq!(high.get(0xa000_0000_0000_0001), 0xa);
+    }
+
+    #[test_case]
+    fn full_width_field() {
+        let field = Field::<u32>::new(0, 32);
+
+        assert_eq!(field.mask(), u32::MAX);
+        assert_eq!(field.get(0x1234_5678), 0x1234_5678);
+    }
+
+    #[test_case]
+    fn register_offsets() {
+        let io = ioremap(PhysAddr::new(TEST_PHYS), 0x1000).unwrap();
+        let regs = TestRegisters::new(io);
+
+        assert_eq!(regs.control().offset(), 0);
+        assert_eq!(regs.control().width(), 1);
+        // Arrays are packed by default
+        assert_eq!(regs.status(3).offset(), 0x10);
+        assert_eq!(regs.status(3).width(), 4);
+        assert_eq!(regs.doorbell(1).offset(), 0x110);
+        assert_eq!(regs.doorbell(1).width(), 2);
+
+        unsafe { iounmap(io) };
+    }
+
+    #[test_case]
+    fn spread_out_register_offsets() {
+        let io = ioremap(PhysAddr::new(TEST_PHYS), 0x1000).unwrap();
+        let layout = Layout {
+            shift: 2,
+            io_width: Some(4),
+        };
+        let regs = TestRegisters::with_layout(io, layout);
+
+        assert_eq!(regs.control().offset(), 0);
+        assert_eq!(regs.control().width(), 4);
+        assert_eq!(regs.status(3).offset(), 0x40);
+        assert_eq!(regs.doorbell(1).offset(), 0x440);
+        assert_eq!(regs.doorbell(1).width(), 4);
+
+        unsafe { iounmap(io) };
+    }
+
+    #[test_case]
+    fn array_bounds() {
+        assert_eq!(array_offset(0x4, 3, 4, 4), Some(0x10));
+        assert_eq!(array_offset(0x4, 4, 4, 4), None);
+        assert_eq!(array_offset(0x100, 0, 0, 0x10), None);
+    }
+}
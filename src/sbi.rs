@@ -1,19 +1,6 @@
 use core::arch::asm;
-use core::fmt;
 
-pub type SbiResult<T> = Result<T, SbiError>;
-
-#[allow(non_upper_case_globals)]
-pub const SbiSuccess: isize = 0;
-
-#[inline]
-pub fn sbi_ret<T>(status: isize, value: T) -> SbiResult<T> {
-    if status == SbiSuccess {
-        Ok(value)
-    } else {
-        Err(SbiError::new(status))
-    }
-}
+pub use dante_core::sbi::{sbi_ret, HartState, SbiError, SbiResult};
 
 const SRST: usize = 0x53525354;
 
@@ -41,68 +28,155 @@ pub fn sbi_shutdown() -> ! {
     sbi_system_reset(0x00000000, 0x00000000)
 }
 
+/// Reset the machine without power cycling it, which keeps the content of the RAM
+#[inline]
+pub fn sbi_warm_reboot() -> ! {
+    sbi_system_reset(0x00000002, 0x00000000)
+}
+
 #[inline]
 pub fn sbi_panic() -> ! {
     sbi_system_reset(0x00000000, 0x00000001)
 }
 
-/// Error codes returned by SBI calls
-///
-/// note: `SBI_SUCCESS` is not represented here since this is to be used as the
-/// error type in a `Result`
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum SbiError {
-    /// The SBI call failed
-    Failed,
-    /// The SBI call is not implemented or the functionality is not available
-    NotSupported,
-    /// An invalid parameter was passed
-    InvalidParameter,
-    /// The SBI implementation has denied execution of the call functionality
-    Denied,
-    /// An invalid address was passed
-    InvalidAddress,
-    /// The resource is already available
-    AlreadyAvailable,
-    /// The resource was previously started
-    AlreadyStarted,
-    /// The resource was previously stopped
-    AlreadyStopped,
-    /// Unknowne error
-    Unknown(isize),
+const TIME: usize = 0x54494D45;
+
+/// Program the clock for the next timer event, at `stime_value` in `time` units
+#[inline]
+pub fn sbi_set_timer(stime_value: u64) -> SbiResult<()> {
+    let status: isize;
+
+    unsafe {
+        asm!(
+            "ecall",
+            in("a7") TIME,
+            in("a6") 0,
+            in("a0") stime_value,
+            lateout("a0") status,
+            lateout("a1") _,
+        )
+    };
+
+    sbi_ret(status, ())
 }
 
-impl SbiError {
-    #[inline]
-    pub fn new(n: isize) -> Self {
-        match n {
-            -1 => SbiError::Failed,
-            -2 => SbiError::NotSupported,
-            -3 => SbiError::InvalidParameter,
-            -4 => SbiError::Denied,
-            -5 => SbiError::InvalidAddress,
-            -6 => SbiError::AlreadyAvailable,
-            -7 => SbiError::AlreadyStarted,
-            -8 => SbiError::AlreadyStopped,
-            n => SbiError::Unknown(n),
-        }
-    }
+const HSM: usize = 0x48534D;
+
+/// Start executing `start_addr` in supervisor mode on `hart_id`, with the MMU
+/// off, `a0` set to `hart_id` and `a1` to `opaque`
+#[inline]
+pub fn sbi_hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> SbiResult<()> {
+    let status: isize;
+
+    unsafe {
+        asm!(
+            "ecall",
+            in("a7") HSM,
+            in("a6") 0,
+            in("a0") hart_id,
+            in("a1") start_addr,
+            in("a2") opaque,
+            lateout("a0") status,
+            lateout("a1") _,
+        )
+    };
+
+    sbi_ret(status, ())
 }
 
-impl fmt::Display for SbiError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let msg = match self {
-            SbiError::AlreadyAvailable => "resource is already available",
-            SbiError::Denied => "SBI implementation denied execution",
-            SbiError::Failed => "call to SBI failed",
-            SbiError::InvalidAddress => "invalid address passed",
-            SbiError::InvalidParameter => "invalid parameter passed",
-            SbiError::NotSupported => "SBI call not implemented or functionality not available",
-            SbiError::AlreadyStarted => "resource was already started",
-            SbiError::AlreadyStopped => "resource was already stopped",
-            SbiError::Unknown(n) => return write!(f, "unknown SBI error code: {n}"),
-        };
-
-        msg.fmt(f)
+/// Return the current hart to the firmware. Only returns on failure.
+#[inline]
+pub fn sbi_hart_stop() -> SbiError {
+    let status: isize;
+
+    unsafe {
+        asm!(
+            "ecall",
+            in("a7") HSM,
+            in("a6") 1,
+            lateout("a0") status,
+            lateout("a1") _,
+        )
+    };
+
+    SbiError::new(status)
+}
+
+#[inline]
+pub fn sbi_hart_get_status(hart_id: usize) -> SbiResult<HartState> {
+    let status: isize;
+    let value: usize;
+
+    unsafe {
+        asm!(
+            "ecall",
+            in("a7") HSM,
+            in("a6") 2,
+            in("a0") hart_id,
+            lateout("a0") status,
+            lateout("a1") value,
+        )
+    };
+
+    sbi_ret(status, HartState::new(value))
+}
+
+const RFENCE: usize = 0x52464E43;
+
+/// Execute `sfence.vma` for `[start, start + size)` on the harts of `hart_mask`,
+/// whose bit 0 is `hart_mask_base`. A base of `usize::MAX` selects every hart.
+#[inline]
+pub fn sbi_remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+) -> SbiResult<()> {
+    let status: isize;
+
+    unsafe {
+        asm!(
+            "ecall",
+            in("a7") RFENCE,
+            in("a6") 1,
+            in("a0") hart_mask,
+            in("a1") hart_mask_base,
+            in("a2") start,
+            in("a3") size,
+            lateout("a0") status,
+            lateout("a1") _,
+        )
+    };
+
+    sbi_ret(status, ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hart;
+
+    #[test_case]
+    fn current_hart_is_started() {
+        assert_eq!(sbi_hart_get_status(hart::id()), Ok(HartState::Started));
+    }
+
+    #[test_case]
+    fn invalid_hart_status() {
+        assert_eq!(
+            sbi_hart_get_status(usize::MAX),
+            Err(SbiError::InvalidParameter)
+        );
+    }
+
+    #[test_case]
+    fn remote_sfence_vma() {
+        assert_eq!(sbi_remote_sfence_vma(0, usize::MAX, 0, usize::MAX), Ok(()));
+    }
+
+    #[test_case]
+    fn set_timer() {
+        // Disarm the timer, which is what an idle hart has
+        assert_eq!(sbi_set_timer(u64::MAX), Ok(()));
     }
 }
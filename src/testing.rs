@@ -0,0 +1,112 @@
+//! Test framework of the test kernel, built by `cargo test --bin dante` (or
+//! `just test`) with `custom_test_frameworks`, which requires a nightly toolchain.
+//!
+//! Every `#[test_case]` runs in QEMU once the kernel is initialized. The first
+//! failing test panics, and the result is reported to the host through the
+//! `sifive,test` device, or SBI SRST when there is none.
+
+use core::any::type_name;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use fdt::Fdt;
+
+use crate::boot::boot_info;
+use crate::memory::ioremap::ioremap;
+use crate::memory::PhysAddr;
+use crate::prelude::*;
+use crate::{debug_print, debug_println, register_block, sbi};
+
+/// Value written to the `sifive,test` device to stop QEMU with exit code 0
+const FINISHER_PASS: u32 = 0x5555;
+/// Value written to the `sifive,test` device to stop QEMU with the exit code in the upper 16 bits
+const FINISHER_FAIL: u32 = 0x3333;
+
+register_block! {
+    /// Registers of the `sifive,test` device
+    struct TestRegisters {
+        /// Stops the machine when written
+        0x0 => finisher: u32, WriteOnly {
+            /// `FINISHER_PASS` or `FINISHER_FAIL`
+            STATUS: 0..16,
+            /// Exit code of QEMU on failure
+            CODE: 16..32,
+        };
+    }
+}
+
+/// The `sifive,test` device, mapped before the tests run. Not behind a lock,
+/// so that the panic handler can use it.
+static FINISHER: AtomicPtr<TestRegisters> = AtomicPtr::new(ptr::null_mut());
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExitCode {
+    Success,
+    Failed,
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        debug_print!("test {} ... ", type_name::<T>());
+        self();
+        debug_println!("ok");
+    }
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    if let Some(finisher) = finisher(&boot_info().fdt) {
+        FINISHER.store(Box::leak(Box::new(finisher)), Ordering::Relaxed);
+    }
+
+    debug_println!("\nrunning {} tests", tests.len());
+
+    for test in tests {
+        test.run();
+    }
+
+    debug_println!("\ntest result: ok. {} passed", tests.len());
+    exit(ExitCode::Success);
+}
+
+/// Called by the panic handler once the failure is reported
+pub fn test_failed() -> ! {
+    debug_println!("\ntest result: FAILED");
+    exit(ExitCode::Failed);
+}
+
+/// Stop the machine, reporting `code` to the host
+pub fn exit(code: ExitCode) -> ! {
+    let finisher = unsafe { FINISHER.load(Ordering::Relaxed).as_ref() };
+
+    if let Some(finisher) = finisher {
+        let value = match code {
+            ExitCode::Success => finisher::STATUS.val(FINISHER_PASS),
+            ExitCode::Failed => finisher::CODE.val(1) | finisher::STATUS.val(FINISHER_FAIL),
+        };
+
+        finisher.finisher().write(value);
+    }
+
+    match code {
+        ExitCode::Success => sbi::sbi_shutdown(),
+        ExitCode::Failed => sbi::sbi_panic(),
+    }
+}
+
+/// The `sifive,test` device
+fn finisher(fdt: &Fdt<'_>) -> Option<TestRegisters> {
+    let node = fdt.find_compatible(&["sifive,test1", "sifive,test0"])?;
+    let reg = node.reg()?.next()?;
+
+    let io = ioremap(
+        PhysAddr::new(reg.starting_address as usize),
+        reg.size.unwrap_or(4),
+    )
+    .ok()?;
+
+    Some(TestRegisters::new(io))
+}
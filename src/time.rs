@@ -0,0 +1,191 @@
+use core::arch::asm;
+use core::fmt;
+use core::ops;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use spinning_top::Spinlock as SpinLock;
+
+use crate::arch::without_interrupts;
+use crate::boot::BootInfo;
+use crate::dtb;
+use crate::sbi::sbi_set_timer;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// `sie.STIE`: supervisor timer interrupt enabled
+const SIE_STIE: usize = 1 << 5;
+
+/// Frequency of the `time` counter, in Hz
+static TIMEBASE_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Whether all harts implement the Sstc extension, which exposes `stimecmp`
+static HAS_SSTC: AtomicBool = AtomicBool::new(false);
+
+/// Called on the hart that received a timer interrupt, with the time it was handled at
+static TIMER_HANDLER: SpinLock<Option<fn(Instant)>> = SpinLock::new(None);
+
+/// A point in time, counted in ticks of the `time` CSR since the machine started
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const MAX: Instant = Instant(u64::MAX);
+
+    pub fn now() -> Self {
+        let ticks: u64;
+        unsafe { asm!("rdtime {0}", out(reg) ticks) };
+        Instant(ticks)
+    }
+
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Instant(ticks)
+    }
+
+    pub const fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// Time elapsed since the machine started
+    pub fn since_boot(&self) -> Duration {
+        ticks_to_duration(self.0)
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration_to_ticks(rhs)))
+    }
+}
+
+impl ops::Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_sub(duration_to_ticks(rhs)))
+    }
+}
+
+impl ops::Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instant({:?})", self.since_boot())
+    }
+}
+
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_boot = self.since_boot();
+        write!(
+            f,
+            "{:5}.{:06}",
+            since_boot.as_secs(),
+            since_boot.subsec_micros()
+        )
+    }
+}
+
+pub fn timebase_frequency() -> u64 {
+    TIMEBASE_FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn has_sstc() -> bool {
+    HAS_SSTC.load(Ordering::Relaxed)
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let frequency = timebase_frequency();
+
+    if frequency == 0 {
+        return Duration::ZERO;
+    }
+
+    let secs = ticks / frequency;
+    let nanos = (ticks % frequency) * NANOS_PER_SEC / frequency;
+    Duration::new(secs, nanos as u32)
+}
+
+fn duration_to_ticks(duration: Duration) -> u64 {
+    let frequency = timebase_frequency() as u128;
+    let ticks = duration.as_nanos() * frequency / NANOS_PER_SEC as u128;
+    ticks.try_into().unwrap_or(u64::MAX)
+}
+
+/// Read the timebase from the `/cpus` node and look for the Sstc extension
+pub fn init(boot_info: &BootInfo) {
+    let fdt = &boot_info.fdt;
+    let frequency = fdt.cpus().next().unwrap().timebase_frequency();
+
+    TIMEBASE_FREQUENCY.store(frequency as u64, Ordering::Relaxed);
+    HAS_SSTC.store(dtb::all_harts_have(fdt, "sstc"), Ordering::Relaxed);
+
+    init_hart();
+}
+
+/// Enable timer interrupts on the current hart, with no timer armed
+pub fn init_hart() {
+    set_timer(Instant::MAX);
+    unsafe { asm!("csrs sie, {0}", in(reg) SIE_STIE) };
+}
+
+/// Raise a timer interrupt on the current hart once `deadline` is reached.
+///
+/// Only one timer is armed per hart: this replaces the previous deadline.
+/// `Instant::MAX` disarms the timer.
+pub fn set_timer(deadline: Instant) {
+    if has_sstc() {
+        // stimecmp
+        unsafe { asm!("csrw 0x14d, {0}", in(reg) deadline.0) };
+    } else {
+        sbi_set_timer(deadline.0).expect("SBI TIME extension unavailable");
+    }
+}
+
+/// Raise a timer interrupt on the current hart after `duration`
+pub fn set_oneshot(duration: Duration) {
+    set_timer(Instant::now() + duration);
+}
+
+/// Install the function called on timer interrupts
+pub fn set_timer_handler(handler: fn(Instant)) {
+    without_interrupts(|| *TIMER_HANDLER.lock() = Some(handler));
+}
+
+/// Wait for at least `duration`, sleeping until the next interrupt in between
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    set_timer(deadline);
+
+    while Instant::now() < deadline {
+        crate::wfi();
+    }
+}
+
+/// Called from the trap handler on supervisor timer interrupts
+pub fn handle_timer_interrupt() {
+    let now = Instant::now();
+
+    // The interrupt stays pending until the timer is moved to the future
+    set_timer(Instant::MAX);
+
+    if let Some(handler) = *TIMER_HANDLER.lock() {
+        handler(now);
+    }
+}
@@ -0,0 +1,299 @@
+use core::arch::{asm, global_asm};
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::arch::MAX_HARTS;
+use crate::memory::VirtAddr;
+use crate::{debug_println, hart, plic, time};
+
+global_asm!(include_str!("trap.s"));
+
+extern "C" {
+    fn _trap_entry();
+}
+
+/// Set when the trap was caused by an interrupt
+const SCAUSE_INTERRUPT: usize = 1 << 63;
+
+/// `sstatus.SPP`: the trap was taken from supervisor mode
+const SSTATUS_SPP: usize = 1 << 8;
+
+/// Trap being handled on each hart, for the panic handler
+static CURRENT_FRAMES: [AtomicPtr<TrapFrame>; MAX_HARTS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_HARTS];
+
+/// ABI names of the general purpose registers
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Interrupted context, saved on the stack by `_trap_entry`.
+///
+/// The layout must match the offsets used in `trap.s`.
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    /// General purpose registers, indexed by their number. `x0` is not saved.
+    pub regs: [usize; 32],
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub scause: usize,
+    pub stval: usize,
+}
+
+impl TrapFrame {
+    pub fn cause(&self) -> Trap {
+        Trap::from_scause(self.scause)
+    }
+
+    pub fn is_kernel(&self) -> bool {
+        self.sstatus & SSTATUS_SPP != 0
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  cause:   {} ({:#x})", self.cause(), self.scause)?;
+        writeln!(f, "  sepc:    {:#018x}", self.sepc)?;
+        writeln!(f, "  stval:   {:#018x}", self.stval)?;
+        writeln!(
+            f,
+            "  sstatus: {:#018x} (from {} mode)",
+            self.sstatus,
+            if self.is_kernel() {
+                "supervisor"
+            } else {
+                "user"
+            }
+        )?;
+
+        for (i, (name, value)) in REGISTER_NAMES.iter().zip(self.regs).enumerate().skip(1) {
+            write!(f, "  {name:>4}: {value:#018x}")?;
+
+            if i % 4 == 3 {
+                writeln!(f)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Trap {
+    Interrupt(Interrupt),
+    Exception(Exception),
+}
+
+impl Trap {
+    pub fn from_scause(scause: usize) -> Self {
+        let code = scause & !SCAUSE_INTERRUPT;
+
+        if scause & SCAUSE_INTERRUPT != 0 {
+            Trap::Interrupt(Interrupt::from_code(code))
+        } else {
+            Trap::Exception(Exception::from_code(code))
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::Interrupt(interrupt) => write!(f, "interrupt: {interrupt}"),
+            Trap::Exception(exception) => write!(f, "exception: {exception}"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Interrupt {
+    SupervisorSoftware,
+    MachineSoftware,
+    SupervisorTimer,
+    MachineTimer,
+    SupervisorExternal,
+    MachineExternal,
+    CounterOverflow,
+    Unknown(usize),
+}
+
+impl Interrupt {
+    pub fn from_code(code: usize) -> Self {
+        match code {
+            1 => Interrupt::SupervisorSoftware,
+            3 => Interrupt::MachineSoftware,
+            5 => Interrupt::SupervisorTimer,
+            7 => Interrupt::MachineTimer,
+            9 => Interrupt::SupervisorExternal,
+            11 => Interrupt::MachineExternal,
+            13 => Interrupt::CounterOverflow,
+            code => Interrupt::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Interrupt::SupervisorSoftware => "supervisor software interrupt",
+            Interrupt::MachineSoftware => "machine software interrupt",
+            Interrupt::SupervisorTimer => "supervisor timer interrupt",
+            Interrupt::MachineTimer => "machine timer interrupt",
+            Interrupt::SupervisorExternal => "supervisor external interrupt",
+            Interrupt::MachineExternal => "machine external interrupt",
+            Interrupt::CounterOverflow => "counter overflow interrupt",
+            Interrupt::Unknown(code) => return write!(f, "unknown interrupt {code}"),
+        };
+
+        msg.fmt(f)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Exception {
+    InstructionMisaligned,
+    InstructionAccessFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadMisaligned,
+    LoadAccessFault,
+    StoreMisaligned,
+    StoreAccessFault,
+    UserEnvCall,
+    SupervisorEnvCall,
+    VirtualSupervisorEnvCall,
+    MachineEnvCall,
+    InstructionPageFault,
+    LoadPageFault,
+    StorePageFault,
+    SoftwareCheck,
+    HardwareError,
+    InstructionGuestPageFault,
+    LoadGuestPageFault,
+    VirtualInstruction,
+    StoreGuestPageFault,
+    Unknown(usize),
+}
+
+impl Exception {
+    pub fn from_code(code: usize) -> Self {
+        match code {
+            0 => Exception::InstructionMisaligned,
+            1 => Exception::InstructionAccessFault,
+            2 => Exception::IllegalInstruction,
+            3 => Exception::Breakpoint,
+            4 => Exception::LoadMisaligned,
+            5 => Exception::LoadAccessFault,
+            6 => Exception::StoreMisaligned,
+            7 => Exception::StoreAccessFault,
+            8 => Exception::UserEnvCall,
+            9 => Exception::SupervisorEnvCall,
+            10 => Exception::VirtualSupervisorEnvCall,
+            11 => Exception::MachineEnvCall,
+            12 => Exception::InstructionPageFault,
+            13 => Exception::LoadPageFault,
+            15 => Exception::StorePageFault,
+            18 => Exception::SoftwareCheck,
+            19 => Exception::HardwareError,
+            20 => Exception::InstructionGuestPageFault,
+            21 => Exception::LoadGuestPageFault,
+            22 => Exception::VirtualInstruction,
+            23 => Exception::StoreGuestPageFault,
+            code => Exception::Unknown(code),
+        }
+    }
+
+    pub fn is_page_fault(&self) -> bool {
+        matches!(
+            self,
+            Exception::InstructionPageFault | Exception::LoadPageFault | Exception::StorePageFault
+        )
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Exception::InstructionMisaligned => "instruction address misaligned",
+            Exception::InstructionAccessFault => "instruction access fault",
+            Exception::IllegalInstruction => "illegal instruction",
+            Exception::Breakpoint => "breakpoint",
+            Exception::LoadMisaligned => "load address misaligned",
+            Exception::LoadAccessFault => "load access fault",
+            Exception::StoreMisaligned => "store/AMO address misaligned",
+            Exception::StoreAccessFault => "store/AMO access fault",
+            Exception::UserEnvCall => "environment call from U-mode",
+            Exception::SupervisorEnvCall => "environment call from S-mode",
+            Exception::VirtualSupervisorEnvCall => "environment call from VS-mode",
+            Exception::MachineEnvCall => "environment call from M-mode",
+            Exception::InstructionPageFault => "instruction page fault",
+            Exception::LoadPageFault => "load page fault",
+            Exception::StorePageFault => "store/AMO page fault",
+            Exception::SoftwareCheck => "software check",
+            Exception::HardwareError => "hardware error",
+            Exception::InstructionGuestPageFault => "instruction guest-page fault",
+            Exception::LoadGuestPageFault => "load guest-page fault",
+            Exception::VirtualInstruction => "virtual instruction",
+            Exception::StoreGuestPageFault => "store/AMO guest-page fault",
+            Exception::Unknown(code) => return write!(f, "unknown exception {code}"),
+        };
+
+        msg.fmt(f)
+    }
+}
+
+/// The trap being handled on the current hart, if any
+pub fn current_frame() -> Option<&'static TrapFrame> {
+    let frame = CURRENT_FRAMES.get(hart::try_id()?)?.load(Ordering::Relaxed);
+
+    unsafe { frame.as_ref() }
+}
+
+/// Install the trap handler on the current hart, without an emergency stack
+pub fn init() {
+    unsafe {
+        asm!("csrw sscratch, zero");
+        asm!("csrw stvec, {0}", in(reg) _trap_entry as *const () as usize);
+    }
+}
+
+/// Have `_trap_entry` switch to the stack ending at `top` when the stack of
+/// the current hart overflowed. Requires `tp` to point to the current hart.
+pub fn set_emergency_stack(top: VirtAddr) {
+    unsafe { asm!("csrw sscratch, {0}", in(reg) top.as_usize()) }
+}
+
+#[no_mangle]
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    let current = &CURRENT_FRAMES[hart::id()];
+    let interrupted = current.swap(frame, Ordering::Relaxed);
+
+    handle_trap(frame);
+
+    current.store(interrupted, Ordering::Relaxed);
+}
+
+fn handle_trap(frame: &mut TrapFrame) {
+    match frame.cause() {
+        Trap::Interrupt(Interrupt::SupervisorTimer) => time::handle_timer_interrupt(),
+        Trap::Interrupt(Interrupt::SupervisorExternal) => plic::handle_external_interrupt(),
+        Trap::Exception(Exception::Breakpoint) => {
+            debug_println!("\n==== BREAKPOINT ====\n{frame}");
+
+            // Skip the `ebreak`, which may be compressed
+            let instruction = unsafe { (frame.sepc as *const u16).read() };
+            frame.sepc += if instruction & 0b11 == 0b11 { 4 } else { 2 };
+        }
+        Trap::Exception(exception)
+            if exception.is_page_fault()
+                && hart::current().is_some_and(|hart| hart.guard_page().contains(&frame.stval)) =>
+        {
+            panic!("kernel stack overflow: {exception} at {:#x}", frame.stval)
+        }
+        trap => panic!("unexpected trap: {trap}"),
+    }
+}
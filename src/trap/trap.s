@@ -0,0 +1,94 @@
+.equ TRAP_FRAME_SIZE, 36 * 8
+.equ TRAP_FRAME_SEPC, 32 * 8
+.equ TRAP_FRAME_SSTATUS, 33 * 8
+.equ TRAP_FRAME_SCAUSE, 34 * 8
+.equ TRAP_FRAME_STVAL, 35 * 8
+
+/* Offset of stack_bottom in Hart (see hart.rs) */
+.equ HART_STACK_BOTTOM, 8
+/* memory::STACK_GUARD_SIZE */
+.equ STACK_GUARD_SIZE, 4096
+
+.section .text.trap, "ax"
+.global _trap_entry
+
+/* Supervisor trap entry, installed in stvec in direct mode.
+ *
+ * Saves the interrupted context in a TrapFrame (see trap/mod.rs) on the
+ * current stack, calls trap_handler with a pointer to it, and resumes from
+ * the possibly modified frame.
+ *
+ * sscratch holds the top of the emergency stack of the hart, or 0. When the
+ * frame would not fit above the stack bottom of the hart (the stack
+ * overflowed into its guard page), the frame is saved on the emergency stack
+ * instead, and sscratch is cleared so that nested traps stay on it. */
+.balign 4
+_trap_entry:
+	csrrw sp, sscratch, sp
+	beqz sp, .Lcurrent_stack
+
+	sd t0, -8(sp)
+	sd t1, -16(sp)
+
+	/* Overflow if the interrupted sp is less than TRAP_FRAME_SIZE above the
+	 * stack bottom, or in the guard page below it */
+	csrr t0, sscratch
+	ld t1, HART_STACK_BOTTOM(tp)
+	sub t0, t0, t1
+	li t1, STACK_GUARD_SIZE
+	add t0, t0, t1
+	li t1, STACK_GUARD_SIZE + TRAP_FRAME_SIZE
+	bltu t0, t1, .Lemergency_stack
+
+	ld t0, -8(sp)
+	ld t1, -16(sp)
+
+.Lcurrent_stack:
+	csrrw sp, sscratch, sp
+	addi sp, sp, -TRAP_FRAME_SIZE
+
+	/* x0 is not saved, x2 (sp) is saved below from its original value */
+	.irp n, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+	sd x\n, \n * 8(sp)
+	.endr
+
+	addi t0, sp, TRAP_FRAME_SIZE
+	sd t0, 2 * 8(sp)
+	j .Lsave_csrs
+
+.Lemergency_stack:
+	ld t0, -8(sp)
+	ld t1, -16(sp)
+	addi sp, sp, -TRAP_FRAME_SIZE
+
+	.irp n, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+	sd x\n, \n * 8(sp)
+	.endr
+
+	csrrw t0, sscratch, zero
+	sd t0, 2 * 8(sp)
+
+.Lsave_csrs:
+	csrr t0, sepc
+	sd t0, TRAP_FRAME_SEPC(sp)
+	csrr t0, sstatus
+	sd t0, TRAP_FRAME_SSTATUS(sp)
+	csrr t0, scause
+	sd t0, TRAP_FRAME_SCAUSE(sp)
+	csrr t0, stval
+	sd t0, TRAP_FRAME_STVAL(sp)
+
+	mv a0, sp
+	call trap_handler
+
+	ld t0, TRAP_FRAME_SEPC(sp)
+	csrw sepc, t0
+	ld t0, TRAP_FRAME_SSTATUS(sp)
+	csrw sstatus, t0
+
+	.irp n, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+	ld x\n, \n * 8(sp)
+	.endr
+
+	addi sp, sp, TRAP_FRAME_SIZE
+	sret